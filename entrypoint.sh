@@ -6,6 +6,16 @@ if [ "${N_THREADS:-0}" = "0" ]; then
   N_THREADS=$(nproc)
 fi
 
+# Context to load every model with. N_CTX takes precedence over CTX; a value
+# below the model's trained context caps it to save KV-cache memory, and 0
+# uses the trained context in full.
+CTX=${N_CTX:-${CTX:-2048}}
+if ! [[ "$CTX" =~ ^[0-9]+$ ]]; then
+  echo "N_CTX/CTX must be a whole number of tokens, got '$CTX'"
+  exit 1
+fi
+export CTX
+
 # Compute MODEL_PATH at runtime from MODEL_FILE if not already set
 if [ -n "${MODEL_FILE:-}" ]; then
   MODEL_PATH=/models/${MODEL_FILE}
@@ -21,60 +31,486 @@ else
   SYSTEM_PROMPT=""
 fi
 
+# Build the list of models to serve. MODELS="name=file.gguf,other=/abs/path.gguf"
+# starts one llama-server per model on consecutive ports from 8080 (server.py
+# routes by the request's model name); otherwise MODEL_PATH is served alone.
+# An entry may end in :template to pick that model's chat template
+# (name=file.gguf:llama3, or a jinja file: name=file.gguf:/prompts/chat.jinja).
+MODEL_SPECS=()
+if [ -n "${MODELS:-}" ]; then
+  IFS=',' read -ra MODEL_SPECS <<< "$MODELS"
+else
+  MODEL_SPECS=("default=$MODEL_PATH")
+fi
+MODEL_LOAD_CONCURRENCY=${MODEL_LOAD_CONCURRENCY:-2}
+[ "$MODEL_LOAD_CONCURRENCY" -lt 1 ] && MODEL_LOAD_CONCURRENCY=1
+MODEL_LOAD_TIMEOUT=${MODEL_LOAD_TIMEOUT:-30}
+
+declare -A MODEL_PORTS=()
+declare -A MODEL_STARTED=()
+declare -A MODEL_PIDS=()
+declare -A MODEL_TEMPLATED=()
+MODEL_NAMES=()
+port=8080
+for spec in "${MODEL_SPECS[@]}"; do
+  spec=$(echo "$spec" | xargs)
+  [ -z "$spec" ] && continue
+  name=${spec%%=*}
+  # A repeated name would start a second llama-server that server.py can never route to
+  if [ -n "${MODEL_PORTS[$name]:-}" ]; then
+    echo "Duplicate model name '$name' in MODELS; each name must be unique"
+    exit 1
+  fi
+  MODEL_NAMES+=("$name")
+  MODEL_PORTS[$name]=$port
+  port=$((port + 1))
+done
+
+model_file() {
+  local spec path
+  for spec in "${MODEL_SPECS[@]}"; do
+    spec=$(echo "$spec" | xargs)
+    if [ "${spec%%=*}" = "$1" ]; then
+      path=${spec#*=}
+      path=${path%%:*}
+      case "$path" in
+        /*) echo "$path" ;;
+        *) echo "/models/$path" ;;
+      esac
+      return
+    fi
+  done
+}
+
+model_template() {
+  local spec value
+  for spec in "${MODEL_SPECS[@]}"; do
+    spec=$(echo "$spec" | xargs)
+    if [ "${spec%%=*}" = "$1" ]; then
+      value=${spec#*=}
+      if [[ "$value" == *:* ]]; then
+        echo "${value#*:}"
+      fi
+      return
+    fi
+  done
+}
+
+# Chat template flags for one model: its own template from MODELS, else the one
+# embedded in the GGUF, else the global CHAT_TEMPLATE/CHAT_TEMPLATE_STRING.
+# GGUF metadata sits at the start of the file, so only its head is searched.
+model_template_args() {
+  local name=$1 template
+  template=$(model_template "$name")
+  if [ -n "$template" ]; then
+    case "$template" in
+      /*) echo "--chat-template-file"; echo "$template" ;;
+      *) echo "--chat-template"; echo "$template" ;;
+    esac
+  elif [ "${#TEMPLATE_ARGS[@]}" -gt 0 ] &&
+      ! grep -aqF tokenizer.chat_template < <(head -c 33554432 "$(model_file "$name")"); then
+    printf '%s\n' "${TEMPLATE_ARGS[@]}"
+  fi
+}
+
+# Default chat template for models without their own: a built-in llama.cpp
+# template name, or a full jinja template string for formats llama.cpp doesn't know
+TEMPLATE_ARGS=()
+if [ -n "${CHAT_TEMPLATE_STRING:-}" ]; then
+  if [ "${JINJA:-true}" != "true" ]; then
+    echo "CHAT_TEMPLATE_STRING requires JINJA=true"
+    exit 1
+  fi
+  printf '%s' "$CHAT_TEMPLATE_STRING" > /tmp/chat_template.jinja
+  TEMPLATE_ARGS=(--chat-template-file /tmp/chat_template.jinja)
+  echo "Default chat template: custom template from CHAT_TEMPLATE_STRING"
+elif [ -n "${CHAT_TEMPLATE:-}" ]; then
+  TEMPLATE_ARGS=(--chat-template "$CHAT_TEMPLATE")
+  echo "Default chat template: built-in '$CHAT_TEMPLATE'"
+fi
+
+# Optionally pin the inference threads to specific cores (e.g. one NUMA node)
+LAUNCHER=()
+if [ -n "${THREAD_AFFINITY:-}" ]; then
+  if command -v taskset > /dev/null; then
+    echo "Pinning llama server threads to CPUs ${THREAD_AFFINITY}"
+    LAUNCHER=(taskset -c "$THREAD_AFFINITY")
+  else
+    echo "THREAD_AFFINITY is set but taskset is unavailable — threads will not be pinned"
+  fi
+fi
+
+# How model weights are held in memory. mmap maps the file and pages weights in
+# lazily (fast start, shared page cache, but evictable under memory pressure);
+# USE_MMAP=false reads the whole file into process memory up front. mlock pins
+# the weights in RAM so they never swap, but can OOM the host if it can't hold
+# them (below the memlock ulimit llama.cpp only warns and runs unlocked).
+MEMORY_ARGS=()
+use_mmap=true use_mlock=false
+if [ "${USE_MMAP:-true}" = "false" ]; then
+  MEMORY_ARGS+=(--no-mmap)
+  use_mmap=false
+fi
+if [ "${USE_MLOCK:-true}" = "true" ]; then
+  MEMORY_ARGS+=(--mlock)
+  use_mlock=true
+fi
+echo "Model memory: mmap=${use_mmap} mlock=${use_mlock}"
+
+# Whether the tokenizer prepends BOS. "auto" keeps the model's own
+# tokenizer.ggml.add_bos_token from its GGUF metadata; true/false override it
+# for models whose metadata gets it wrong, which degrades output silently.
+BOS_ARGS=()
+case "${ADD_BOS:-auto}" in
+  auto) echo "BOS token: as set in each model's GGUF metadata" ;;
+  true|false)
+    BOS_ARGS=(--override-kv "tokenizer.ggml.add_bos_token=bool:${ADD_BOS}")
+    echo "BOS token: add_bos=${ADD_BOS} (overriding GGUF metadata)"
+    ;;
+  *)
+    echo "ADD_BOS must be auto, true or false, got '$ADD_BOS'"
+    exit 1
+    ;;
+esac
+
+# GPU placement, for images built on a GPU-enabled llama.cpp (the default image
+# is CPU-only). N_GPU_LAYERS offloads layers; MAIN_GPU picks the device for a
+# single-GPU model (and for scratch buffers when split); TENSOR_SPLIT spreads
+# the model over several GPUs in the given ratios, e.g. "3,1".
+GPU_ARGS=()
+if [ -n "${N_GPU_LAYERS:-}" ]; then
+  GPU_ARGS+=(--n-gpu-layers "$N_GPU_LAYERS")
+fi
+if [ -n "${MAIN_GPU:-}" ]; then
+  if ! [[ "$MAIN_GPU" =~ ^[0-9]+$ ]]; then
+    echo "MAIN_GPU must be a device index, got '$MAIN_GPU'"
+    exit 1
+  fi
+  GPU_ARGS+=(--main-gpu "$MAIN_GPU")
+fi
+if [ -n "${TENSOR_SPLIT:-}" ]; then
+  if ! [[ "$TENSOR_SPLIT" =~ ^[0-9]+(\.[0-9]+)?(,[0-9]+(\.[0-9]+)?)*$ ]]; then
+    echo "TENSOR_SPLIT must be comma-separated ratios such as 3,1 — got '$TENSOR_SPLIT'"
+    exit 1
+  fi
+  GPU_ARGS+=(--tensor-split "$TENSOR_SPLIT")
+fi
+if [ "${#GPU_ARGS[@]}" -gt 0 ]; then
+  echo "GPU placement: layers=${N_GPU_LAYERS:-default} main_gpu=${MAIN_GPU:-0} tensor_split=${TENSOR_SPLIT:-none}"
+fi
+
+# TOKENIZER_ONLY=true serves tokenization cheaply: no decode runs against the
+# weights (no warmup), nothing is mlocked, the context is minimal and no layers go
+# to a GPU. The file is still mapped and prefetched into the page cache.
+# server.py refuses generation in this mode.
+TOKENIZER_ARGS=()
+if [ "${TOKENIZER_ONLY:-false}" = "true" ]; then
+  echo "TOKENIZER_ONLY is set — serving tokenization only; generation endpoints are disabled"
+  MEMORY_ARGS=()
+  GPU_ARGS=()
+  TOKENIZER_ARGS=(--no-warmup -c 256 --parallel 1)
+  unset WARMUP_PROMPT
+fi
+
+# Load failures, one JSON object per line, for server.py's GET /admin/model-status.
+# Each llama server's output is also kept in MODEL_LOG_DIR so a crash's own error
+# message can be recorded.
+MODEL_ERRORS_FILE=/tmp/mindforge-model-errors.jsonl
+MODEL_LOG_DIR=/tmp/mindforge-llama-logs
+: > "$MODEL_ERRORS_FILE"
+mkdir -p "$MODEL_LOG_DIR"
+# Left by POST /admin/abort-all?not_ready=true; a fresh start is ready again
+rm -f /tmp/mindforge-not-ready
+
+record_load_error() {
+  python3 -c 'import json, sys; print(json.dumps({"model": sys.argv[1], "path": sys.argv[2], "error": sys.argv[3]}))' \
+    "$1" "$2" "$3" >> "$MODEL_ERRORS_FILE"
+}
+
+start_model() {
+  local name=$1 path extra=() template_args=()
+  path=$(model_file "$name")
+  mapfile -t template_args < <(model_template_args "$name")
+  MODEL_TEMPLATED[$name]=${#template_args[@]}
+  if [ "${#template_args[@]}" -gt 0 ]; then
+    echo "Model '$name' uses chat template ${template_args[1]}"
+  fi
+  # Embedding models run llama-server in embeddings-only mode with the chosen pooling
+  if [[ ",${EMBEDDING_MODELS:-}," == *",$name,"* ]]; then
+    extra+=(--embeddings --pooling "${EMBEDDING_POOLING:-mean}")
+  fi
+  # Render special/control tokens in the output; server.py strips them again
+  # for requests that don't ask for the raw token stream
+  if [ "${SKIP_SPECIAL_TOKENS:-true}" = "false" ]; then
+    extra+=(--special)
+  fi
+  # Roll the KV cache (discarding the oldest tokens) instead of stopping when it fills
+  if [ "${CONTEXT_SHIFT:-false}" = "true" ]; then
+    extra+=(--context-shift)
+  fi
+  # Jinja chat templates are required for tools/tool_calls support
+  if [ "${JINJA:-true}" = "true" ]; then
+    extra+=(--jinja)
+  fi
+  echo "Starting llama server for model '$name' ($path) on port ${MODEL_PORTS[$name]}"
+  "${LAUNCHER[@]}" /app/llama-server \
+    "${extra[@]}" \
+    "${template_args[@]}" \
+    "${MEMORY_ARGS[@]}" \
+    "${GPU_ARGS[@]}" \
+    "${BOS_ARGS[@]}" \
+    -m "$path" \
+    -c "$CTX" \
+    -b "$N_BATCH" \
+    -t "$N_THREADS" \
+    --parallel "$N_PARALLEL" \
+    "${TOKENIZER_ARGS[@]}" \
+    --host 0.0.0.0 \
+    --port "${MODEL_PORTS[$name]}" > >(tee "$MODEL_LOG_DIR/$name.log") 2>&1 &
+  MODEL_PIDS[$name]=$!
+  MODEL_STARTED[$name]=$(date +%s)
+}
+
+# Log the model's trained context next to the context it was loaded with, which
+# is what server.py validates requests against.
+log_context() {
+  local name=$1 trained
+  trained=$(curl -sf "http://localhost:${MODEL_PORTS[$name]}/v1/models" | python3 -c '
+import json, sys
+try:
+    print(json.load(sys.stdin)["data"][0]["meta"]["n_ctx_train"])
+except Exception:
+    pass
+' || true)
+  if [ -z "$trained" ]; then
+    echo "Model '$name': context ${CTX} (trained context unknown)"
+  elif [ "$CTX" -eq 0 ]; then
+    echo "Model '$name': trained context ${trained}, loaded with the full ${trained}"
+  elif [ "$CTX" -gt "$trained" ]; then
+    echo "Model '$name': WARNING context ${CTX} exceeds the trained context ${trained}; output degrades past ${trained} tokens"
+  else
+    echo "Model '$name': trained context ${trained}, capped to ${CTX}"
+  fi
+}
+
+# Models currently holding a load slot. Loads are memory-heavy, so at most
+# MODEL_LOAD_CONCURRENCY may be in progress at once; a slot frees as soon as
+# its model is up, fails, or times out (timed-out loads are killed so their
+# memory is released before the next load starts).
+LOADING=()
+
+poll_loading() {
+  local still_loading=() name elapsed error
+  for name in "${LOADING[@]}"; do
+    elapsed=$(( $(date +%s) - ${MODEL_STARTED[$name]} ))
+    if curl -sf "http://localhost:${MODEL_PORTS[$name]}/health" > /dev/null; then
+      echo "Model '$name' is up (loaded in ${elapsed}s)"
+      log_context "$name"
+      MODEL_OK=1
+      MODEL_READY+=("$name")
+    elif ! kill -0 "${MODEL_PIDS[$name]}" 2>/dev/null; then
+      echo "Model '$name': file $(model_file "$name") exists but llama.cpp failed to load it (corrupt or unsupported GGUF$([ "${MODEL_TEMPLATED[$name]}" -gt 0 ] && echo ", or an invalid chat template")?) — see the llama server output above."
+      error=$(grep -iE 'error|failed|exception' "$MODEL_LOG_DIR/$name.log" 2>/dev/null | tail -n 3 | tr '\n' ' ')
+      record_load_error "$name" "$(model_file "$name")" "${error:-llama.cpp exited while loading the model}"
+      MODEL_FAILED=1
+    elif [ "$elapsed" -ge "$MODEL_LOAD_TIMEOUT" ]; then
+      echo "Timed out waiting for model '$name' after ${MODEL_LOAD_TIMEOUT}s — stopping it"
+      record_load_error "$name" "$(model_file "$name")" "load timed out after ${MODEL_LOAD_TIMEOUT}s"
+      kill "${MODEL_PIDS[$name]}" 2>/dev/null || true
+      MODEL_FAILED=1
+    else
+      still_loading+=("$name")
+    fi
+  done
+  LOADING=("${still_loading[@]}")
+}
+
+# Optional warmup: one representative completion per model. With the system
+# prompt from SYSTEM_PROMPT_PATH it also leaves that prefix in llama.cpp's
+# prompt cache, and the logged speed is an early performance sanity check.
+warmup_model() {
+  local name=$1 body started result
+  body=$(WARMUP_PROMPT="$WARMUP_PROMPT" WARMUP_TOKENS="${WARMUP_TOKENS:-16}" SYSTEM_PROMPT="$SYSTEM_PROMPT" python3 -c '
+import json, os
+messages = [{"role": "user", "content": os.environ["WARMUP_PROMPT"]}]
+if os.environ["SYSTEM_PROMPT"]:
+    messages.insert(0, {"role": "system", "content": os.environ["SYSTEM_PROMPT"]})
+print(json.dumps({"messages": messages, "max_tokens": int(os.environ["WARMUP_TOKENS"]), "cache_prompt": True}))')
+  started=$(date +%s.%N)
+  if ! result=$(curl -sf --max-time 300 -H "Content-Type: application/json" -d "$body" \
+      "http://localhost:${MODEL_PORTS[$name]}/v1/chat/completions"); then
+    echo "Warmup for model '$name' failed — continuing"
+    WARMUP_RESULTS+=("$name=failed")
+    return
+  fi
+  WARMUP_RESULTS+=("$name=done")
+  WARMUP_STARTED="$started" python3 -c '
+import json, os, sys, time
+data = json.load(sys.stdin)
+timings = data.get("timings") or {}
+tokens = timings.get("predicted_n") or (data.get("usage") or {}).get("completion_tokens", 0)
+rate = timings.get("predicted_per_second") or tokens / max(time.time() - float(os.environ["WARMUP_STARTED"]), 1e-6)
+prompt_rate = timings.get("prompt_per_second")
+print(f"Warmup for model {sys.argv[1]!r}: {tokens} tokens at {rate:.1f} tokens/s"
+      + (f" (prompt {prompt_rate:.1f} tokens/s)" if prompt_rate else ""))' "$name" <<< "$result" \
+    || echo "Warmup for model '$name' returned an unexpected response"
+}
+
+# Block until fewer than $1 loads are in progress
+wait_for_load_slots() {
+  while [ "${#LOADING[@]}" -ge "$1" ]; do
+    sleep 1
+    poll_loading
+  done
+}
+
 # Informational startup
 echo "Starting llama server with absolute path..."
 
 MODEL_OK=0
-# Start the llama server in the background if possible
-if [ -x /app/llama-server ]; then
-  if [ -f "$MODEL_PATH" ]; then
-    echo "Starting llama server with model $MODEL_PATH"
-    /app/llama-server \
-      -m "$MODEL_PATH" \
-      -c "$CTX" \
-      -b "$N_BATCH" \
-      -t "$N_THREADS" \
-      --parallel "$N_PARALLEL" \
-      --mlock \
-      --host 0.0.0.0 \
-      --port 8080 &
-    LLAMA_PID=$!
-    MODEL_OK=1
-  else
-    echo "Model file $MODEL_PATH not found — skipping LLM server startup. FastAPI will run in degraded mode."
+MODEL_FAILED=0
+MODEL_READY=()
+WARMUP_RESULTS=()
+# Start the llama servers in the background if possible, holding a load slot
+# per model so large files don't OOM the host. MOCK_MODEL=true needs none:
+# server.py answers upstream calls itself with canned replies.
+if [ "${MOCK_MODEL:-false}" = "true" ]; then
+  echo "MOCK_MODEL is set — serving canned responses without llama.cpp or a model file"
+  MODEL_OK=1
+elif [ -x /app/llama-server ]; then
+  for name in "${MODEL_NAMES[@]}"; do
+    path=$(model_file "$name")
+    if [ ! -e "$path" ]; then
+      echo "Model file does not exist: $(realpath -m "$path") — skipping model '$name'. Check MODEL_FILE/MODELS and the /models mount."
+      record_load_error "$name" "$(realpath -m "$path")" "model file does not exist"
+      MODEL_FAILED=1
+      continue
+    fi
+    if [ ! -f "$path" ] || [ "$(head -c 4 "$path")" != "GGUF" ]; then
+      echo "Model file exists but is not a GGUF file: $(realpath -m "$path") — skipping model '$name'."
+      record_load_error "$name" "$(realpath -m "$path")" "not a GGUF file"
+      MODEL_FAILED=1
+      continue
+    fi
+    wait_for_load_slots "$MODEL_LOAD_CONCURRENCY"
+    start_model "$name"
+    LOADING+=("$name")
+  done
+  echo "Waiting for LLM server to start..."
+  wait_for_load_slots 1
+  if [ "$MODEL_OK" -eq 0 ]; then
+    echo "No model loaded — FastAPI will run in degraded mode."
   fi
+  if [ -n "${WARMUP_PROMPT:-}" ]; then
+    for name in "${MODEL_READY[@]}"; do
+      # Embedding-only servers can't generate
+      [[ ",${EMBEDDING_MODELS:-}," == *",$name,"* ]] && continue
+      warmup_model "$name"
+    done
+  fi
+  # uvicorn starts only after this, so the API never takes traffic for a model
+  # mid-warmup; /healthz reports how each warmup went
+  MODEL_WARMUP=$(IFS=','; echo "${WARMUP_RESULTS[*]}")
+  export MODEL_WARMUP
 else
   echo "LLM server executable not found at /app/llama-server — running FastAPI only (degraded mode)."
+  for name in "${MODEL_NAMES[@]}"; do
+    record_load_error "$name" "$(model_file "$name")" "llama-server executable not found at /app/llama-server"
+  done
+  MODEL_FAILED=1
 fi
 
-# If LLM server started, wait for it to be healthy; otherwise continue
-if [ "$MODEL_OK" -eq 1 ]; then
-  echo "Waiting for LLM server to start..."
-  for i in {1..30}; do
-    if curl -s http://localhost:8080/ > /dev/null; then
-      echo "LLM server is up!"
-      break
-    fi
-    if [ $i -eq 30 ]; then
-      echo "Timed out waiting for LLM server — continuing with FastAPI in degraded mode"
-      MODEL_OK=0
-      break
-    fi
-    sleep 1
+# Fail fast instead of running in degraded mode so a crash-looping container
+# makes a misconfigured model obvious
+if [ "${FAIL_ON_MODEL_LOAD_ERROR:-false}" = "true" ] && [ "$MODEL_FAILED" -eq 1 ]; then
+  echo "FAIL_ON_MODEL_LOAD_ERROR is set and a model failed to load — exiting."
+  for pid in "${MODEL_PIDS[@]}"; do
+    kill "$pid" 2>/dev/null || true
   done
+  exit 1
 fi
 
 # Start the FastAPI service with optimized settings
 echo "Starting FastAPI service..."
 export PYTHONOPTIMIZE=2
+# Stored conversations live in one worker's memory; a follow-up routed to another
+# worker would not find its conversation, so they need a single worker
+if [ "${CONVERSATIONS:-false}" = "true" ]; then
+  if [ -n "${UVICORN_WORKERS:-}" ] && [ "$UVICORN_WORKERS" != 1 ]; then
+    echo "CONVERSATIONS=true requires UVICORN_WORKERS=1 (got $UVICORN_WORKERS): conversations are not shared between workers" >&2
+    exit 1
+  fi
+  export UVICORN_WORKERS=1
+fi
 export UVICORN_WORKERS=${UVICORN_WORKERS:-$(nproc)}
 export UVICORN_LOOP=uvloop
 export UVICORN_HTTP=httptools
 
-if [ "$MODEL_OK" -eq 1 ]; then
+if [ "${MOCK_MODEL:-false}" = "true" ]; then
+  echo "FastAPI will answer from the mock model"
+elif [ "$MODEL_OK" -eq 1 ]; then
   echo "FastAPI will proxy to local LLM server on port 8080"
 else
   echo "FastAPI running in degraded mode: upstream LLM unavailable"
 fi
 
-exec python3 -m uvicorn server:app --host 0.0.0.0 --port 3000 --workers $UVICORN_WORKERS
+SHUTDOWN_GRACE_SECONDS=${SHUTDOWN_GRACE_SECONDS:-30}
+SIGINT_GRACE_SECONDS=${SIGINT_GRACE_SECONDS:-5}
+DRAIN_DELAY_SECONDS=${DRAIN_DELAY_SECONDS:-0}
+for grace in "$SHUTDOWN_GRACE_SECONDS" "$SIGINT_GRACE_SECONDS" "$DRAIN_DELAY_SECONDS"; do
+  case "$grace" in
+    ''|*[!0-9]*) echo "Shutdown grace periods and DRAIN_DELAY_SECONDS must be whole seconds, got '$grace'" >&2; exit 1 ;;
+  esac
+done
+
+python3 -m uvicorn server:app --host 0.0.0.0 --port 3000 --workers $UVICORN_WORKERS &
+UVICORN_PID=$!
+
+# Signal every uvicorn worker; with several workers the uvicorn supervisor
+# doesn't handle SIGHUP or SIGUSR1, so signal its children directly
+signal_workers() {
+  if [ "$UVICORN_WORKERS" -gt 1 ]; then
+    for pid in $(cat /proc/"$UVICORN_PID"/task/*/children 2>/dev/null); do
+      kill -"$1" "$pid" 2>/dev/null || true
+    done
+  else
+    kill -"$1" "$UVICORN_PID" 2>/dev/null || true
+  fi
+}
+
+# SIGHUP reloads prompts and config in every worker
+reload_workers() {
+  echo "SIGHUP received — reloading prompts and configuration"
+  signal_workers HUP
+}
+trap reload_workers HUP
+
+# SIGTERM (docker stop, orchestrators) and SIGINT (Ctrl-C in local dev) both
+# start uvicorn's graceful drain; once the signal's grace period passes, a
+# second SIGINT makes uvicorn stop waiting on in-flight requests. Shutdown
+# hooks still run either way, so the summary line and logs are not lost.
+# Before that, SIGUSR1 flips /healthz to 503 "draining"; on SIGTERM the API
+# keeps serving for DRAIN_DELAY_SECONDS so load balancers can stop routing here.
+stop_uvicorn() {
+  echo "$1 received — draining for up to $2s"
+  signal_workers USR1
+  if [ "$1" = SIGTERM ] && [ "$DRAIN_DELAY_SECONDS" -gt 0 ]; then
+    echo "Readiness reports draining; still serving for ${DRAIN_DELAY_SECONDS}s"
+    sleep "$DRAIN_DELAY_SECONDS"
+  fi
+  kill -TERM "$UVICORN_PID" 2>/dev/null || true
+  (
+    sleep "$2"
+    for pid in $(cat /proc/"$UVICORN_PID"/task/*/children 2>/dev/null) "$UVICORN_PID"; do
+      kill -INT "$pid" 2>/dev/null || true
+    done
+  ) &
+}
+trap 'stop_uvicorn SIGTERM "$SHUTDOWN_GRACE_SECONDS"' TERM
+trap 'stop_uvicorn SIGINT "$SIGINT_GRACE_SECONDS"' INT
+
+status=0
+while kill -0 "$UVICORN_PID" 2>/dev/null; do
+  wait "$UVICORN_PID" && status=0 || status=$?
+done
+exit $status
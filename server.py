@@ -1,150 +1,3664 @@
 import os
+import re
 import json
+import math
+import random
+import sys
 import time
+import signal
+import heapq
+import hashlib
+import hmac
+import ipaddress
+import asyncio
+import codecs
+import glob
+import itertools
+import logging
 import httpx
+import yaml
+from collections import OrderedDict
 
-from fastapi import FastAPI, HTTPException
-from fastapi.responses import StreamingResponse
-from pydantic import BaseModel
-from typing import List, Optional
+from fastapi import FastAPI, HTTPException, Query, Request, Response
+from fastapi.exceptions import RequestValidationError
+from fastapi.exception_handlers import request_validation_exception_handler
+from fastapi.responses import JSONResponse, PlainTextResponse, StreamingResponse
+from starlette.background import BackgroundTask
+from starlette.middleware.cors import CORSMiddleware
+from pydantic import AliasChoices, BaseModel, Field
+from typing import Callable, List, Optional, Union
 from functools import lru_cache
+try:
+    # Python 3.11 moved the regex parser; sre_parse still works but warns
+    from re import _constants as sre_constants, _parser as sre_parse
+except ImportError:
+    import sre_constants
+    import sre_parse
+from prometheus_client import Counter
 from prometheus_fastapi_instrumentator import Instrumentator
+from opentelemetry import trace
+
+
+logging.basicConfig(level=os.getenv("LOG_LEVEL", "INFO").upper())
+logger = logging.getLogger("mindforge")
+
+# Spans are no-ops unless an OTLP endpoint is configured via the standard
+# OpenTelemetry environment variables
+tracer = trace.get_tracer("mindforge")
+if os.getenv("OTEL_EXPORTER_OTLP_ENDPOINT") or os.getenv("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"):
+    from opentelemetry.sdk.resources import Resource
+    from opentelemetry.sdk.trace import TracerProvider
+    from opentelemetry.sdk.trace.export import BatchSpanProcessor
+    from opentelemetry.exporter.otlp.proto.http.trace_exporter import OTLPSpanExporter
+
+    _provider = TracerProvider(resource=Resource.create(
+        {"service.name": os.getenv("OTEL_SERVICE_NAME", "mindforge-server-llm")}))
+    _provider.add_span_processor(BatchSpanProcessor(OTLPSpanExporter()))
+    trace.set_tracer_provider(_provider)
+
+
+def env_bool(name: str, default: bool = False) -> bool:
+    return os.getenv(name, str(default)).strip().lower() in ("1", "true", "yes", "on")
+
+
+def env_float(name: str) -> Optional[float]:
+    value = os.getenv(name, "").strip()
+    return float(value) if value else None
+
+
+__version__ = "0.2.0"
+
+UPSTREAM_BASE_PORT = 8080
+PROMPTS_DIR = "/prompts"
+
+
+def parse_models(spec: str) -> dict:
+    """Parse MODELS ("name=file.gguf,other=/abs/path.gguf:llama3") into name -> upstream info.
+
+    Each model gets its own llama-server started by entrypoint.sh on
+    UPSTREAM_BASE_PORT + position, so the order here must match the entrypoint.
+    An optional `:template` suffix is the model's chat template, applied there.
+    Repeated names are rejected: ports are positional, so the later entry would
+    silently shadow the earlier one.
+    """
+    models = {}
+    for index, entry in enumerate(e.strip() for e in spec.split(",") if e.strip()):
+        name, _, path = entry.partition("=")
+        path, _, template = path.partition(":")
+        if name.strip() in models:
+            raise ValueError(f"Duplicate model name {name.strip()!r} in MODELS")
+        models[name.strip()] = {
+            "path": path.strip(),
+            "template": template.strip() or None,
+            "url": f"http://localhost:{UPSTREAM_BASE_PORT + index}",
+        }
+    return models
+
+
+def parse_aliases(spec: str) -> dict:
+    """Parse MODEL_ALIASES="gpt-3.5-turbo=phi3,gpt-4=phi3" into {alias: model}."""
+    aliases = {}
+    for entry in (e.strip() for e in spec.split(",") if e.strip()):
+        alias, _, model = entry.partition("=")
+        aliases[alias.strip()] = model.strip()
+    return aliases
+
+
+def parse_model_limits(spec: str) -> dict:
+    """Parse per-model numeric limits ("phi3=256,llama3=1024") into {model: int}."""
+    return {model: int(value) for model, value in parse_aliases(spec).items()}
+
+
+def load_env_file(path: str):
+    """Apply KEY=VALUE lines from an env file to os.environ."""
+    with open(path, 'r', encoding='utf-8') as f:
+        for line in f:
+            line = line.strip()
+            if not line or line.startswith("#") or "=" not in line:
+                continue
+            key, _, value = line.partition("=")
+            os.environ[key.strip()] = value.strip().strip('"').strip("'")
+
+
+class Settings:
+    """Runtime configuration read from environment variables."""
+
+    def __init__(self):
+        # Prompt/completion logging is sensitive, so it is strictly opt-in
+        self.log_prompts = env_bool("LOG_PROMPTS")
+        # LOG_PROMPTS_MAX_CHARS is the older spelling, still honoured when the new one is unset
+        self.log_prompts_max_chars = int(
+            os.getenv("LOG_PROMPT_MAX_CHARS") or os.getenv("LOG_PROMPTS_MAX_CHARS") or "0"
+        )
+        # Fraction of successful chat completions logged in full; slow and failed ones always are
+        self.log_sample_rate = min(1.0, max(0.0, float(os.getenv("LOG_SAMPLE_RATE", "1.0"))))
+        redact = os.getenv("LOG_REDACT_PATTERN", "")
+        self.log_redact_pattern = re.compile(redact) if redact else None
+        # Seconds clients are told to wait (and new requests are refused) after an upstream OOM
+        self.oom_retry_after = int(os.getenv("OOM_RETRY_AFTER", "5"))
+        # Retries (with exponential backoff) for transient upstream failures before erroring
+        self.upstream_retries = int(os.getenv("UPSTREAM_RETRIES", "2"))
+        self.upstream_retry_backoff = float(os.getenv("UPSTREAM_RETRY_BACKOFF_SECONDS", "0.2"))
+        # Optional chargeback pricing; cost is only reported when a price is configured
+        self.price_per_1k_prompt = env_float("PRICE_PER_1K_PROMPT_TOKENS")
+        self.price_per_1k_completion = env_float("PRICE_PER_1K_COMPLETION_TOKENS")
+        # Optional multi-model map; empty means a single upstream serving every model name
+        self.models = parse_models(os.getenv("MODELS", ""))
+        # Warmup outcome per model ("phi3=done,llama3=failed"), exported by entrypoint.sh
+        self.model_warmup = parse_aliases(os.getenv("MODEL_WARMUP", ""))
+        # Names clients hardcode (e.g. gpt-3.5-turbo) mapped onto served models
+        self.model_aliases = parse_aliases(os.getenv("MODEL_ALIASES", ""))
+        self.advertise_aliases = env_bool("ADVERTISE_MODEL_ALIASES")
+        self.response_id_prefix = os.getenv("RESPONSE_ID_PREFIX", "chatcmpl-")
+        self.embeddings_max_inputs = int(os.getenv("EMBEDDINGS_MAX_INPUTS", "256"))
+        # max_tokens when a request omits it, and the most it may ask for (0 = no cap);
+        # the per-model maps override these for the models they name
+        self.default_max_tokens = int(os.getenv("DEFAULT_MAX_TOKENS", "100"))
+        self.max_tokens_limit = int(os.getenv("MAX_TOKENS_LIMIT", "0"))
+        self.model_default_max_tokens = parse_model_limits(os.getenv("MODEL_DEFAULT_MAX_TOKENS", ""))
+        self.model_max_tokens_limit = parse_model_limits(os.getenv("MODEL_MAX_TOKENS_LIMIT", ""))
+        # Mirrors entrypoint.sh, which starts these llama-servers in embeddings-only mode
+        self.embedding_models = [m.strip() for m in os.getenv("EMBEDDING_MODELS", "").split(",") if m.strip()]
+        # entrypoint.sh passes --jinja unless JINJA=false; tool calls need it
+        self.jinja = env_bool("JINJA", True)
+        # Upper bound on best_of (and n): each candidate is a full generation
+        self.max_best_of = int(os.getenv("MAX_BEST_OF", "4"))
+        # Per-model caps on n and best_of, for models where extra candidates are costly
+        self.model_max_n = parse_model_limits(os.getenv("MODEL_MAX_N", ""))
+        self.model_max_best_of = parse_model_limits(os.getenv("MODEL_MAX_BEST_OF", ""))
+        self.logits_max_top_k = int(os.getenv("LOGITS_MAX_TOP_K", "100"))
+        # Generated tokens per stream event, unless the request sets stream_chunk_tokens
+        self.stream_chunk_tokens = int(os.getenv("STREAM_CHUNK_TOKENS", "1"))
+        # Bounds on stop_regex cost: pattern length, and how much trailing output it scans
+        self.stop_regex_max_length = int(os.getenv("STOP_REGEX_MAX_LENGTH", "256"))
+        # Every stop string is checked against each chunk, so bound how many and how long
+        self.max_stop_strings = int(os.getenv("MAX_STOP_STRINGS", "4"))
+        self.max_stop_total_chars = int(os.getenv("MAX_STOP_TOTAL_CHARS", "256"))
+        # End generation with finish_reason "repetition" once an n-gram of NGRAM_SIZE words
+        # occurs more than NGRAM_REPEAT_LIMIT times (0 disables); requests may override both
+        self.ngram_repeat_limit = int(os.getenv("NGRAM_REPEAT_LIMIT", "0"))
+        self.ngram_size = int(os.getenv("NGRAM_SIZE", "8"))
+        self.stop_regex_window = int(os.getenv("STOP_REGEX_WINDOW", "1024"))
+        # Byte cap on each choice's decoded output, for downstream byte limits (0 = unlimited)
+        self.max_output_bytes = int(os.getenv("MAX_OUTPUT_BYTES", "0"))
+        # Seconds of upstream silence (e.g. while a long prompt is ingested) before a keepalive
+        self.stream_keepalive = float(os.getenv("STREAM_KEEPALIVE_SECONDS", "5"))
+        self.prompt_include_max_depth = int(os.getenv("PROMPT_INCLUDE_MAX_DEPTH", "5"))
+        # Temperatures below this are sent as 0, which llama.cpp treats as greedy decoding
+        self.temperature_floor = float(os.getenv("TEMPERATURE_FLOOR", "0.01"))
+        # What to do with model output that isn't valid UTF-8: replace with U+FFFD, or strip
+        self.output_encoding_policy = os.getenv("OUTPUT_ENCODING_POLICY", "replace").strip().lower()
+        if self.output_encoding_policy not in ("replace", "strip"):
+            raise ValueError(f"OUTPUT_ENCODING_POLICY must be replace or strip, "
+                             f"got {self.output_encoding_policy!r}")
+        # Ask llama.cpp to keep evaluated prompts cached and report system prompt reuse
+        self.system_prompt_cache = env_bool("SYSTEM_PROMPT_CACHE", True)
+        # Server-wide sampler defaults for fields a request omits
+        top_k = env_float("DEFAULT_TOP_K")
+        self.default_sampler = {
+            "temperature": env_float("DEFAULT_TEMPERATURE"),
+            "top_p": env_float("DEFAULT_TOP_P"),
+            "top_k": int(top_k) if top_k is not None else None,
+            "repeat_penalty": env_float("DEFAULT_REPEAT_PENALTY"),
+        }
+        # Policy cap on prompt size, independent of the model's context (0 disables)
+        self.max_prompt_tokens = int(os.getenv("MAX_PROMPT_TOKENS", "0"))
+        self.auto_truncate = env_bool("AUTO_TRUNCATE")
+        # Must match the entrypoint: CONTEXT_SHIFT=true lets generation run past a full context
+        self.context_shift = env_bool("CONTEXT_SHIFT")
+        self.merge_consecutive_roles = env_bool("MERGE_CONSECUTIVE_ROLES")
+        self.merge_roles_separator = os.getenv("MERGE_ROLES_SEPARATOR", "\n\n")
+        # Refuse chat requests for models with no chat template instead of using llama.cpp's default
+        self.require_chat_template = env_bool("REQUIRE_CHAT_TEMPLATE")
+        # Must match the entrypoint: SKIP_SPECIAL_TOKENS=false starts llama-server with --special
+        self.skip_special_tokens = env_bool("SKIP_SPECIAL_TOKENS", True)
+        # Opt-in output filter: completions matching the pattern finish with content_filter
+        output_filter = os.getenv("OUTPUT_FILTER_PATTERN", "")
+        self.output_filter_pattern = re.compile(output_filter) if output_filter else None
+        self.output_filter_action = os.getenv("OUTPUT_FILTER_ACTION", "redact")
+        # Ordered completion post-processing stages (see POST_PROCESSORS)
+        self.post_processors = [name.strip() for name in os.getenv(
+            "POST_PROCESSORS", "strip_special_tokens,empty_completion,content_filter").split(",")
+            if name.strip()]
+        # Conversations live in one worker's memory, so entrypoint.sh only allows them with one worker
+        self.conversations = env_bool("CONVERSATIONS")
+        self.conversation_ttl = int(os.getenv("CONVERSATION_TTL_SECONDS", "3600"))
+        self.conversation_max = int(os.getenv("CONVERSATION_MAX", "1000"))
+        # Per-client-IP cap on in-flight API requests (0 disables); X-Forwarded-For is
+        # only believed when the connection comes from one of TRUSTED_PROXIES
+        self.max_requests_per_ip = int(os.getenv("MAX_REQUESTS_PER_IP", "0"))
+        self.trusted_proxies = [ipaddress.ip_network(p.strip(), strict=False)
+                                for p in os.getenv("TRUSTED_PROXIES", "").split(",") if p.strip()]
+        # Optional API keys (Authorization: Bearer or X-API-Key); empty leaves the API open.
+        # Exempt paths match exactly, or as a prefix when they end in *
+        self.api_keys = [k.strip() for k in os.getenv("API_KEYS", "").split(",") if k.strip()]
+        self.auth_exempt_paths = [p.strip() for p in os.getenv("AUTH_EXEMPT_PATHS", "/,/healthz,/metrics").split(",")
+                                  if p.strip()]
+        # Key for X-Admin-Key on destructive admin endpoints; unset leaves them disabled
+        self.admin_api_key = os.getenv("ADMIN_API_KEY", "")
+        # Concurrency limit with a bounded wait queue in front of it (0 disables)
+        self.max_concurrent_requests = int(os.getenv("MAX_CONCURRENT_REQUESTS", "0"))
+        self.queue_max_depth = int(os.getenv("QUEUE_MAX_DEPTH", "16"))
+        self.queue_max_wait = float(os.getenv("QUEUE_MAX_WAIT_SECONDS", "30"))
+        # Separate cap on open streams, which hold a permit for their whole life (0 disables)
+        self.max_concurrent_streams = int(os.getenv("MAX_CONCURRENT_STREAMS", "0"))
+        self.stream_retry_after = int(os.getenv("STREAM_RETRY_AFTER_SECONDS", "5"))
+        # Completions slower than this are logged at warning and counted (0 disables)
+        self.slow_request_ms = int(os.getenv("SLOW_REQUEST_MS", "0"))
+        # X-Inflight-Requests / X-Available-Permits on responses, for clients that pace themselves
+        self.load_headers = env_bool("LOAD_HEADERS", True)
+        # Load shedding: shrink max_tokens once more requests are in flight than slots
+        self.adaptive_max_tokens = env_bool("ADAPTIVE_MAX_TOKENS")
+        self.adaptive_max_tokens_floor = int(os.getenv("ADAPTIVE_MAX_TOKENS_FLOOR", "32"))
+        self.adaptive_max_tokens_threshold = int(os.getenv("ADAPTIVE_MAX_TOKENS_THRESHOLD",
+                                                           os.getenv("N_PARALLEL", "1")))
+        # MOCK_MODEL itself is read once at import; these shape the canned replies
+        self.mock_response = os.getenv("MOCK_RESPONSE", "Mock reply: {prompt_tail}")
+        self.mock_prompt_tail_chars = int(os.getenv("MOCK_PROMPT_TAIL_CHARS", "64"))
+        self.mock_token_delay = float(os.getenv("MOCK_TOKEN_DELAY_SECONDS", "0"))
+
+
+if os.getenv("CONFIG_FILE") and os.path.exists(os.environ["CONFIG_FILE"]):
+    load_env_file(os.environ["CONFIG_FILE"])
+settings = Settings()
+
+# MOCK_MODEL=true answers every llama-server call in-process with deterministic canned
+# replies, so the whole HTTP layer (routes, validation, streaming, error handling)
+# can be exercised in CI without a GGUF. It replaces the transport, not any route.
+MOCK_MODEL = env_bool("MOCK_MODEL")
+# TOKENIZER_ONLY=true: entrypoint.sh starts llama-server with a tiny context and no
+# warmup or GPU offload, so only tokenization is served and generation endpoints refuse requests
+TOKENIZER_ONLY = env_bool("TOKENIZER_ONLY")
+# Paths that still work in that mode; /healthz, /metrics and the root are always available
+TOKENIZER_ONLY_PATHS = ("/v1/tokenize", "/v1/detokenize", "/v1/models")
+# A last user message starting with this makes the mock upstream fail with that status
+MOCK_ERROR_PATTERN = re.compile(r"^mock:error:(\d{3})")
+# With tools in the request, one starting with this calls that tool (arguments: the rest, as JSON)
+MOCK_TOOL_PATTERN = re.compile(r"^mock:tool:(\w+)\s*(.*)$", re.DOTALL)
+
+
+def mock_tokens(text: str) -> List[str]:
+    # One "token" per word, keeping its trailing whitespace so tokens join back to the text
+    return re.findall(r"\s*\S+\s*", text)
+
+
+def mock_text(message: dict) -> str:
+    content = message.get("content") or ""
+    if isinstance(content, list):
+        content = " ".join(part.get("text", "") for part in content if isinstance(part, dict))
+    return content
+
+
+def mock_template(messages: List[dict]) -> str:
+    turns = [f"<|{message.get('role')}|>\n{mock_text(message)}" for message in messages]
+    # Like llama.cpp, a final assistant message is a prefill that the reply continues
+    if messages and messages[-1].get("role") == "assistant":
+        return "\n".join(turns)
+    return "\n".join(turns + ["<|assistant|>\n"])
+
+
+def mock_reply(messages: List[dict]) -> str:
+    """MOCK_RESPONSE with {prompt_tail} replaced by the end of the last user message."""
+    last = next((m for m in reversed(messages) if m.get("role") == "user"), {})
+    content = mock_text(last)
+    tail = content[-settings.mock_prompt_tail_chars:] if settings.mock_prompt_tail_chars > 0 else ""
+    return settings.mock_response.replace("{prompt_tail}", tail)
+
+
+# A seeded, sampling mock request ends its reply with one of these, picked by the seed
+MOCK_SAMPLED_WORDS = ("red", "green", "blue", "amber", "violet", "teal", "ochre", "indigo")
+
+
+def mock_sample(text: str, body: dict) -> str:
+    """Make the reply depend on the seed the way sampling would; greedy requests ignore it."""
+    if body.get("seed") is None or not body.get("temperature"):
+        return text
+    digest = hashlib.sha256(str(body["seed"]).encode()).digest()
+    return f"{text} {MOCK_SAMPLED_WORDS[digest[0] % len(MOCK_SAMPLED_WORDS)]}"
+
+
+def mock_generate(text: str, max_tokens: Optional[int], stop) -> tuple:
+    """Apply stop strings and the token limit the way llama.cpp would; returns (tokens, finish)."""
+    stops = [stop] if isinstance(stop, str) else stop or []
+    cuts = [text.find(s) for s in stops if s and s in text]
+    if cuts:
+        text = text[:min(cuts)]
+    tokens = mock_tokens(text)
+    if max_tokens and max_tokens > 0 and len(tokens) > max_tokens:
+        return tokens[:max_tokens], "length"
+    return tokens, "stop"
+
+
+def mock_embedding(text: str) -> List[float]:
+    vector = [b / 255 - 0.5 for b in hashlib.sha256(text.encode()).digest()[:16]]
+    norm = math.sqrt(sum(v * v for v in vector)) or 1.0
+    return [v / norm for v in vector]
+
+
+async def mock_upstream(request: httpx.Request) -> httpx.Response:
+    """Stand-in for llama-server's endpoints behind httpx.MockTransport."""
+    path = request.url.path
+    body = json.loads(request.content or b"{}") if request.method == "POST" else {}
+    messages = body.get("messages") or []
+    last_user = next((mock_text(m) for m in reversed(messages) if m.get("role") == "user"), "")
+    failure = MOCK_ERROR_PATTERN.match(last_user or body.get("prompt") or "")
+    if failure and path in ("/v1/chat/completions", "/v1/completions", "/completion"):
+        status = int(failure.group(1))
+        return httpx.Response(status, json={"error": {"code": status, "message": f"Mock error {status}",
+                                                      "type": "server_error"}})
+
+    if path in ("/", "/health"):
+        return httpx.Response(200, json={"status": "ok"})
+    if path == "/props":
+        return httpx.Response(200, json={
+            "model_path": "/models/mock.gguf",
+            "build_info": "mock",
+            "default_generation_settings": {"n_ctx": int(os.getenv("N_CTX") or os.getenv("CTX", "2048")),
+                                            "params": {"temperature": 0.8, "top_k": 40, "top_p": 0.95,
+                                                       "typical_p": 1.0}},
+        })
+    if path == "/tokenize":
+        words = mock_tokens(body.get("content") or "")
+        if body.get("with_pieces"):
+            return httpx.Response(200, json={"tokens": [{"id": i, "piece": w} for i, w in enumerate(words)]})
+        return httpx.Response(200, json={"tokens": list(range(len(words)))})
+    if path == "/detokenize":
+        return httpx.Response(200, json={"content": " ".join(f"tok{t}" for t in body.get("tokens") or [])})
+    if path == "/apply-template":
+        return httpx.Response(200, json={"prompt": mock_template(messages)})
+    if path == "/v1/embeddings":
+        inputs = [body["input"]] if isinstance(body.get("input"), str) else body.get("input") or []
+        count = sum(len(mock_tokens(text)) for text in inputs)
+        return httpx.Response(200, json={
+            "object": "list", "model": body.get("model") or "mock",
+            "data": [{"object": "embedding", "index": i, "embedding": mock_embedding(text)}
+                     for i, text in enumerate(inputs)],
+            "usage": {"prompt_tokens": count, "total_tokens": count},
+        })
+    if path == "/completion":
+        prompt = body.get("prompt") or ""
+        tokens, finish = mock_generate(mock_reply([{"role": "user", "content": prompt}]),
+                                       body.get("n_predict"), body.get("stop"))
+        data = {"content": "".join(tokens), "stop": True, "tokens_predicted": len(tokens),
+                "tokens_evaluated": len(mock_tokens(prompt)), "stop_type": "limit" if finish == "length" else "eos"}
+        if body.get("n_probs"):
+            # The reply's first token is the most likely, followed by filler candidates
+            first = tokens[0] if tokens else ""
+            candidates = [{"id": i, "token": first if i == 0 else f" mock{i}", "logprob": (i + 1) * math.log(0.5)}
+                          for i in range(body["n_probs"])]
+            data["completion_probabilities"] = [{**candidates[0], "top_logprobs": candidates}]
+        return httpx.Response(200, json=data)
+    if path == "/v1/completions":
+        prompt = body.get("prompt") or ""
+        tokens, finish = mock_generate(mock_sample(mock_reply([{"role": "user", "content": prompt}]), body),
+                                       body.get("max_tokens"), body.get("stop"))
+        usage = {"prompt_tokens": len(mock_tokens(prompt)), "completion_tokens": len(tokens),
+                 "total_tokens": len(mock_tokens(prompt)) + len(tokens)}
+        envelope = {"id": "cmpl-mock", "object": "text_completion", "created": int(time.time()),
+                    "model": body.get("model") or "mock"}
+        if not body.get("stream"):
+            return httpx.Response(200, json={**envelope, "usage": usage, "choices": [
+                {"index": 0, "text": "".join(tokens), "logprobs": None, "finish_reason": finish}]})
+
+        async def text_events():
+            for index, token in enumerate(tokens + [""]):
+                if token and settings.mock_token_delay > 0:
+                    await asyncio.sleep(settings.mock_token_delay)
+                last = index == len(tokens)
+                event = {**envelope, "choices": [{"index": 0, "text": token, "logprobs": None,
+                                                  "finish_reason": finish if last else None}]}
+                if last and (body.get("stream_options") or {}).get("include_usage"):
+                    event["usage"] = usage
+                yield f"data: {json.dumps(event)}\n\n".encode()
+            yield b"data: [DONE]\n\n"
+
+        return httpx.Response(200, headers={"content-type": "text/event-stream"}, content=text_events())
+    if path != "/v1/chat/completions":
+        return httpx.Response(404, json={"error": {"code": 404, "message": "File Not Found"}})
+
+    tokens, finish = mock_generate(mock_sample(mock_reply(messages), body), body.get("max_tokens"),
+                                   body.get("stop"))
+    tool_call = MOCK_TOOL_PATTERN.match(last_user) if body.get("tools") else None
+    if tool_call:
+        tokens, finish = [], "tool_calls"
+        tool_call = {"id": "call_mock", "type": "function",
+                     "function": {"name": tool_call.group(1), "arguments": tool_call.group(2) or "{}"}}
+    prompt = mock_tokens(mock_template(messages))
+    prompt_tokens = len(prompt)
+    usage = {"prompt_tokens": prompt_tokens, "completion_tokens": len(tokens),
+             "total_tokens": prompt_tokens + len(tokens)}
+    # Like llama.cpp's prompt cache, reuse the prefix shared with the previous prompt
+    cached = 0
+    if body.get("cache_prompt"):
+        previous = mock_upstream.last_prompt
+        while cached < min(len(prompt), len(previous)) - 1 and prompt[cached] == previous[cached]:
+            cached += 1
+    mock_upstream.last_prompt = prompt
+    timings = {"cache_n": cached, "prompt_n": prompt_tokens - cached, "predicted_n": len(tokens)}
+    envelope = {"id": "chatcmpl-mock", "created": int(time.time()), "model": body.get("model") or "mock"}
+    if not body.get("stream"):
+        choice = {"index": 0, "finish_reason": finish,
+                  "message": {"role": "assistant", "content": "".join(tokens) if tokens or not tool_call else None}}
+        if tool_call:
+            choice["message"]["tool_calls"] = [tool_call]
+        if body.get("logprobs"):
+            choice["logprobs"] = {"content": [{"token": token, "logprob": -0.1} for token in tokens]}
+        return httpx.Response(200, json={**envelope, "object": "chat.completion", "choices": [choice],
+                                         "usage": usage, "timings": timings})
+
+    def chunk(delta: dict, finish_reason: Optional[str] = None, **extra) -> bytes:
+        event = {**envelope, "object": "chat.completion.chunk", **extra,
+                 "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}]}
+        return f"data: {json.dumps(event)}\n\n".encode()
+
+    async def events():
+        yield chunk({"role": "assistant", "content": None})
+        for token in tokens:
+            if settings.mock_token_delay > 0:
+                await asyncio.sleep(settings.mock_token_delay)
+            yield chunk({"content": token})
+        if tool_call:
+            # Name first, then the arguments in two pieces, as llama.cpp streams them
+            arguments = tool_call["function"]["arguments"]
+            yield chunk({"tool_calls": [{"index": 0, "id": tool_call["id"], "type": "function",
+                                         "function": {"name": tool_call["function"]["name"], "arguments": ""}}]})
+            for piece in (arguments[:len(arguments) // 2], arguments[len(arguments) // 2:]):
+                yield chunk({"tool_calls": [{"index": 0, "function": {"arguments": piece}}]})
+        yield chunk({}, finish, timings=timings)
+        if (body.get("stream_options") or {}).get("include_usage"):
+            yield f"data: {json.dumps({**envelope, 'object': 'chat.completion.chunk', 'choices': [], 'usage': usage})}\n\n".encode()
+        yield b"data: [DONE]\n\n"
+
+    return httpx.Response(200, headers={"content-type": "text/event-stream"}, content=events())
+
+mock_upstream.last_prompt = []
 
 
 # Create a global client for connection pooling
-http_client = httpx.AsyncClient(timeout=60.0)
-app = FastAPI()
+if MOCK_MODEL:
+    logger.warning("MOCK_MODEL is set: serving canned responses, no model is used")
+    http_client = httpx.AsyncClient(timeout=60.0, transport=httpx.MockTransport(mock_upstream))
+else:
+    http_client = httpx.AsyncClient(timeout=60.0)
+app = FastAPI(version=__version__)
+
+# Instrument metrics at import time so middleware is registered before Uvicorn
+Instrumentator().instrument(app).expose(app)
+SLOW_REQUESTS = Counter("mindforge_slow_requests_total",
+                        "Chat completions that took longer than SLOW_REQUEST_MS", ["model"])
+SYSTEM_PROMPT_CACHE = Counter("mindforge_system_prompt_cache_total",
+                              "Chat completions with a system prompt, by whether llama.cpp reused "
+                              "its cached evaluation", ["model", "result"])
+
+# Content types careless clients send with JSON bodies (curl -d defaults to form encoding)
+LENIENT_CONTENT_TYPES = ("", "text/plain", "application/x-www-form-urlencoded")
+
+
+@app.middleware("http")
+async def tolerate_json_content_type(request: Request, call_next):
+    """Treat POST bodies to the API as JSON when the content type is missing or generic."""
+    if request.method == "POST" and request.url.path.startswith("/v1/"):
+        content_type = request.headers.get("content-type", "").split(";")[0].strip().lower()
+        if content_type in LENIENT_CONTENT_TYPES:
+            headers = [(k, v) for k, v in request.scope["headers"] if k != b"content-type"]
+            headers.append((b"content-type", b"application/json"))
+            request.scope["headers"] = headers
+    return await call_next(request)
+
+
+@app.middleware("http")
+async def refuse_generation_when_tokenizer_only(request: Request, call_next):
+    """In TOKENIZER_ONLY mode llama-server is sized for tokenizing only, so nothing may generate."""
+    if TOKENIZER_ONLY and request.url.path.startswith("/v1/") \
+            and not request.url.path.startswith(TOKENIZER_ONLY_PATHS):
+        return JSONResponse(status_code=501, content={
+            "detail": f"This instance runs with TOKENIZER_ONLY=true and only serves "
+                      f"{', '.join(TOKENIZER_ONLY_PATHS)}"})
+    return await call_next(request)
+
+
+@app.middleware("http")
+async def mark_mock_responses(request: Request, call_next):
+    """Label responses while MOCK_MODEL is on, so canned output can't pass for a model's."""
+    response = await call_next(request)
+    if MOCK_MODEL:
+        response.headers["X-Mock-Model"] = "true"
+    return response
+
+
+def is_trusted_proxy(host: str) -> bool:
+    try:
+        address = ipaddress.ip_address(host)
+    except ValueError:
+        return False
+    return any(address in network for network in settings.trusted_proxies)
+
+
+def client_ip(request: Request) -> str:
+    """The requesting host: the connection peer, or behind trusted proxies the
+    right-most X-Forwarded-For address that isn't itself a trusted proxy."""
+    host = request.client.host if request.client else ""
+    if not is_trusted_proxy(host):
+        return host
+    forwarded = [h.strip() for h in request.headers.get("x-forwarded-for", "").split(",") if h.strip()]
+    for hop in reversed(forwarded):
+        if not is_trusted_proxy(hop):
+            return hop
+    return forwarded[0] if forwarded else host
+
+
+requests_per_ip = {}
+
+
+@app.middleware("http")
+async def limit_requests_per_ip(request: Request, call_next):
+    """Cap concurrent API requests (including open streams) from a single client IP."""
+    if settings.max_requests_per_ip <= 0 or not request.url.path.startswith("/v1/"):
+        return await call_next(request)
+    ip = client_ip(request)
+    if requests_per_ip.get(ip, 0) >= settings.max_requests_per_ip:
+        logger.warning("Rejecting request from %s: %d requests already in flight", ip, requests_per_ip[ip])
+        return JSONResponse(status_code=429, headers={"Retry-After": "1"}, content={
+            "detail": f"Too many concurrent requests from {ip} (limit {settings.max_requests_per_ip})"})
+    requests_per_ip[ip] = requests_per_ip.get(ip, 0) + 1
+    released = False
+
+    def release():
+        nonlocal released
+        if released:
+            return
+        released = True
+        requests_per_ip[ip] -= 1
+        if requests_per_ip[ip] <= 0:
+            del requests_per_ip[ip]
+
+    try:
+        response = await call_next(request)
+    except Exception:
+        release()
+        raise
+    # Streams stay in flight until their body is done
+    body = response.body_iterator
+
+    async def body_then_release():
+        try:
+            async for chunk in body:
+                yield chunk
+        finally:
+            release()
+
+    response.body_iterator = body_then_release()
+    response.background = BackgroundTask(release)
+    return response
+
+
+def auth_exempt(path: str) -> bool:
+    return any(path.startswith(p[:-1]) if p.endswith("*") else path == p for p in settings.auth_exempt_paths)
+
+
+@app.middleware("http")
+async def require_api_key(request: Request, call_next):
+    """Check API_KEYS before anything else runs, so rejected clients use no per-IP slots.
+
+    CORS preflights carry no credentials by design and are always let through.
+    """
+    if not settings.api_keys or request.method == "OPTIONS" or auth_exempt(request.url.path):
+        return await call_next(request)
+    authorization = request.headers.get("authorization", "")
+    key = authorization[len("bearer "):].strip() if authorization.lower().startswith("bearer ") \
+        else request.headers.get("x-api-key", "")
+    if not any(hmac.compare_digest(key.encode(), valid.encode()) for valid in settings.api_keys):
+        return JSONResponse(status_code=401, headers={"WWW-Authenticate": "Bearer"},
+                            content={"detail": "Missing or invalid API key"})
+    return await call_next(request)
+
+
+# Added after every other middleware so it is the outermost: preflights are answered
+# before auth runs, and 401/429 responses still get CORS headers. Read once at startup.
+CORS_ALLOW_ORIGINS = [o.strip() for o in os.getenv("CORS_ALLOW_ORIGINS", "").split(",") if o.strip()]
+if CORS_ALLOW_ORIGINS:
+    app.add_middleware(CORSMiddleware, allow_origins=CORS_ALLOW_ORIGINS, allow_methods=["*"],
+                       allow_headers=["*"], expose_headers=["*"])
+
+
+@app.exception_handler(RequestValidationError)
+async def validation_error_handler(request: Request, exc: RequestValidationError):
+    for error in exc.errors():
+        if error.get("type") == "json_invalid":
+            position = error.get("loc", ("body", "?"))[-1]
+            reason = (error.get("ctx") or {}).get("error", "invalid JSON")
+            return JSONResponse(status_code=400, content={
+                "detail": f"Request body is not valid JSON ({reason} at position {position}); "
+                          f"expected Content-Type: application/json"})
+    return await request_validation_exception_handler(request, exc)
+
+
+def list_prompts() -> List[str]:
+    if not os.path.isdir(PROMPTS_DIR):
+        return []
+    return sorted(f[:-len(".txt")] for f in os.listdir(PROMPTS_DIR) if f.endswith(".txt"))
+
+
+def reload_config():
+    """SIGHUP handler: drop cached prompt templates and re-read reloadable settings.
+
+    Settings are re-read from the environment after applying CONFIG_FILE (if set).
+    The model map is not reloaded; models are owned by the llama servers.
+    """
+    global settings
+    logger.info("SIGHUP received, reloading prompts and configuration")
+    before = list_prompts()
+    read_prompt_file.cache_clear()
+    prompt_metadata.cache_clear()
+    after = list_prompts()
+    added, removed = sorted(set(after) - set(before)), sorted(set(before) - set(after))
+    logger.info("Prompts: %d available (added: %s, removed: %s)",
+                len(after), added or "none", removed or "none")
+
+    config_file = os.getenv("CONFIG_FILE")
+    if config_file:
+        try:
+            load_env_file(config_file)
+        except OSError as e:
+            logger.error("Could not read CONFIG_FILE %s: %s", config_file, e)
+            return
+    try:
+        new_settings = Settings()
+    except (ValueError, re.error) as e:
+        logger.error("Invalid configuration, keeping previous settings: %s", e)
+        return
+    new_settings.models = settings.models
+    changed = sorted(k for k, v in vars(new_settings).items() if vars(settings).get(k) != v)
+    settings = new_settings
+    logger.info("Configuration reloaded (changed: %s)", ", ".join(changed) or "none")
+
+
+# Set when a graceful shutdown starts: readiness fails so traffic moves elsewhere,
+# while liveness (/) stays healthy until the process exits
+draining = False
+
+
+def start_draining():
+    """SIGUSR1 handler, sent by entrypoint.sh at the start of a graceful shutdown."""
+    global draining
+    if not draining:
+        draining = True
+        logger.info("Draining: /healthz now reports 503 until shutdown")
+
+
+@app.on_event("startup")
+async def install_reload_handler():
+    asyncio.get_running_loop().add_signal_handler(signal.SIGHUP, reload_config)
+    asyncio.get_running_loop().add_signal_handler(signal.SIGUSR1, start_draining)
+    asyncio.get_running_loop().add_signal_handler(signal.SIGUSR2, abort_all_inferences)
+
+
+# Add shutdown event to close client
+@app.on_event("shutdown")
+async def shutdown_event():
+    await http_client.aclose()
+    served = completion_stats["requests"]
+    average = completion_stats["latency_ms"] / served if served else 0
+    logger.info("Shutting down: served %d chat completions, average latency %d ms",
+                served, average)
+
+
+class Message(BaseModel):
+    role: str
+    # Assistant messages that only carry tool_calls have no content
+    content: Optional[str] = None
+    # Participant name in group chats, or the tool name for `tool` messages
+    name: Optional[str] = None
+    tool_calls: Optional[List[dict]] = None
+    tool_call_id: Optional[str] = None
+
+def aliased(default, *aliases: str):
+    """A field that also accepts other clients' spellings (sending two of them is rejected)."""
+    return Field(default, validation_alias=AliasChoices(*aliases))
+
+
+class ChatRequest(BaseModel):
+    model: str
+    messages: List[Message]
+    # Omitted sampler fields fall back to the DEFAULT_* env vars, then SAMPLER_DEFAULTS
+    temperature: Optional[float] = None
+    top_p: Optional[float] = aliased(None, "top_p", "topP")
+    top_k: Optional[int] = aliased(None, "top_k", "topK")
+    repeat_penalty: Optional[float] = aliased(None, "repeat_penalty", "repetition_penalty", "repeatPenalty")
+    # Fixes llama.cpp's sampler RNG; echoed in the response (see System Fingerprint in README)
+    seed: Optional[int] = None
+    # Omitted: the model's default, then DEFAULT_MAX_TOKENS (see resolve_max_tokens)
+    max_tokens: Optional[int] = aliased(None, "max_tokens", "max_completion_tokens", "maxTokens")
+    tools: Optional[List[dict]] = None
+    tool_choice: Optional[Union[str, dict]] = aliased(None, "tool_choice", "toolChoice")
+    n_ctx: Optional[int] = None
+    mirostat: Optional[int] = None
+    mirostat_tau: Optional[float] = None
+    mirostat_eta: Optional[float] = None
+    # Locally typical and tail-free sampling; 1.0 turns either off
+    typical_p: Optional[float] = None
+    tfs_z: Optional[float] = None
+    stream_chunk_tokens: Optional[int] = None
+    merge_consecutive_roles: Optional[bool] = None
+    skip_special_tokens: Optional[bool] = None
+    conversation_id: Optional[str] = aliased(None, "conversation_id", "conversationId")
+    stream_options: Optional[dict] = aliased(None, "stream_options", "streamOptions")
+    # Choices to return, and candidates to generate and rank by summed logprob
+    n: Optional[int] = None
+    best_of: Optional[int] = aliased(None, "best_of", "bestOf")
+    # Up to MAX_STOP_STRINGS stop strings; whitespace runs in them match any whitespace run
+    stop: Optional[Union[str, List[str]]] = aliased(None, "stop", "stop_sequences", "stopSequences")
+    stop_case_insensitive: Optional[bool] = None
+    # Stop once the output matches this pattern (in addition to llama.cpp's stop strings)
+    stop_regex: Optional[str] = None
+    # Loop guard overriding NGRAM_REPEAT_LIMIT / NGRAM_SIZE; a limit of 0 turns it off
+    ngram_repeat_limit: Optional[int] = None
+    ngram_size: Optional[int] = None
+    # Queue tier when MAX_CONCURRENT_REQUESTS is set: high, normal (default) or low
+    priority: Optional[str] = None
+
+    class Config:
+        # Optimize validation
+        validate_assignment = True
+        extra = "forbid"
+
+class EmbeddingRequest(BaseModel):
+    model: str
+    input: Union[str, List[str]]
+    encoding_format: Optional[str] = "float"
+
+    class Config:
+        extra = "forbid"
+
+class LogitsRequest(BaseModel):
+    model: str
+    # Either a raw prompt, or messages formatted with the model's chat template
+    prompt: Optional[str] = None
+    messages: Optional[List[Message]] = None
+    top_k: int = 10
+
+    class Config:
+        extra = "forbid"
+
+class TokenizeRequest(BaseModel):
+    model: str
+    # Either raw text, or messages formatted with the model's chat template (as /v1/logits)
+    content: Optional[str] = None
+    messages: Optional[List[Message]] = None
+    # Also return each token's text
+    with_pieces: bool = False
+
+    class Config:
+        extra = "forbid"
+
+class DetokenizeRequest(BaseModel):
+    model: str
+    tokens: List[int]
+
+    class Config:
+        extra = "forbid"
+
+class CompletionRequest(BaseModel):
+    """OpenAI's legacy text completion: a raw prompt, no chat template."""
+    model: str
+    prompt: str
+    temperature: Optional[float] = None
+    top_p: Optional[float] = aliased(None, "top_p", "topP")
+    top_k: Optional[int] = aliased(None, "top_k", "topK")
+    repeat_penalty: Optional[float] = aliased(None, "repeat_penalty", "repetition_penalty", "repeatPenalty")
+    seed: Optional[int] = None
+    max_tokens: Optional[int] = aliased(None, "max_tokens", "maxTokens")
+    stop: Optional[Union[str, List[str]]] = aliased(None, "stop", "stop_sequences", "stopSequences")
+    stream: bool = False
+    stream_options: Optional[dict] = aliased(None, "stream_options", "streamOptions")
+    priority: Optional[str] = None
+
+    class Config:
+        extra = "forbid"
+
+class AnthropicMessage(BaseModel):
+    role: str
+    content: Union[str, List[dict]]
+
+class AnthropicRequest(BaseModel):
+    model: str
+    max_tokens: int
+    messages: List[AnthropicMessage]
+    system: Optional[Union[str, List[dict]]] = None
+    temperature: Optional[float] = None
+    stream: Optional[bool] = False
+
+    class Config:
+        # Unsupported Anthropic fields (metadata, top_k, ...) are accepted and ignored
+        extra = "ignore"
+
+PROMPT_NAME_PATTERN = re.compile(r"^[A-Za-z0-9_\-]+$")
+INCLUDE_PATTERN = re.compile(r"\{\{\s*include:\s*([^}\s]+)\s*\}\}")
+VARIABLE_PATTERN = re.compile(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}")
+# Optional YAML block between two `---` lines at the very top of a template
+FRONT_MATTER_PATTERN = re.compile(r"\A---[ \t]*\r?\n(.*?)^---[ \t]*(?:\r?\n|\Z)", re.S | re.M)
+
+
+def prompt_path_for(prompt_name: str) -> Optional[str]:
+    """Resolve a template name to its file, or None if it would leave the prompts dir."""
+    if not PROMPT_NAME_PATTERN.match(prompt_name):
+        return None
+    prompt_path = os.path.realpath(f"{PROMPTS_DIR}/{prompt_name}.txt")
+    if os.path.dirname(prompt_path) != os.path.realpath(PROMPTS_DIR):
+        return None
+    return prompt_path
+
+
+def read_prompt_source(prompt_name: str, prompt_path: str) -> str:
+    try:
+        with open(prompt_path, 'r', encoding='utf-8') as f:
+            return f.read()
+    except UnicodeDecodeError as e:
+        logger.error("Prompt template %s is not valid UTF-8 (byte offset %d)", prompt_path, e.start)
+        raise HTTPException(status_code=500,
+                            detail=f"Prompt template {prompt_name} is not valid UTF-8: "
+                                   f"invalid byte 0x{e.object[e.start]:02x} at offset {e.start}")
+
+
+def split_front_matter(prompt_name: str, source: str) -> tuple:
+    """Separate a template's front-matter mapping from its text; ({}, source) without one."""
+    match = FRONT_MATTER_PATTERN.match(source)
+    if match is None:
+        return {}, source
+    try:
+        meta = yaml.safe_load(match.group(1)) or {}
+    except yaml.YAMLError as e:
+        raise HTTPException(status_code=500,
+                            detail=f"Prompt template {prompt_name} has invalid front-matter: {e}")
+    if not isinstance(meta, dict) or not isinstance(meta.get("defaults", {}), dict):
+        raise HTTPException(status_code=500,
+                            detail=f"Prompt template {prompt_name} front-matter must be a mapping "
+                                   f"with an optional `defaults` mapping")
+    return meta, source[match.end():]
+
+
+def render_prompt(prompt_name: str, stack: List[str]) -> str:
+    """Read a template and expand its {{include:other_template}} directives."""
+    prompt_path = prompt_path_for(prompt_name)
+    if prompt_path is None:
+        if stack:
+            raise HTTPException(status_code=500,
+                                detail=f"Prompt template {stack[-1]} includes invalid template name {prompt_name}")
+        raise HTTPException(status_code=400, detail=f"Invalid prompt template name {prompt_name}")
+    if not os.path.exists(prompt_path):
+        if stack:
+            raise HTTPException(status_code=500,
+                                detail=f"Prompt template {stack[-1]} includes missing template {prompt_name}")
+        raise HTTPException(status_code=404, detail=f"Prompt template {prompt_name} not found")
+    if prompt_name in stack:
+        raise HTTPException(status_code=500,
+                            detail=f"Prompt template include cycle: {' -> '.join(stack + [prompt_name])}")
+    if len(stack) > settings.prompt_include_max_depth:
+        raise HTTPException(status_code=500,
+                            detail=f"Prompt template includes nested deeper than "
+                                   f"{settings.prompt_include_max_depth}: {' -> '.join(stack + [prompt_name])}")
+    meta, source = split_front_matter(prompt_name, read_prompt_source(prompt_name, prompt_path))
+    text = INCLUDE_PATTERN.sub(lambda m: render_prompt(m.group(1), stack + [prompt_name]), source)
+    # Placeholders without a default are left as written
+    defaults = meta.get("defaults", {})
+    return VARIABLE_PATTERN.sub(lambda m: str(defaults.get(m.group(1), m.group(0))), text)
+
+
+@lru_cache(maxsize=32)
+def read_prompt_file(prompt_name: str) -> str:
+    return render_prompt(prompt_name, [])
+
+
+@lru_cache(maxsize=32)
+def prompt_metadata(prompt_name: str) -> dict:
+    """The catalog entry for a template: its name plus description, model and defaults if set."""
+    prompt_path = prompt_path_for(prompt_name)
+    meta, _ = split_front_matter(prompt_name, read_prompt_source(prompt_name, prompt_path))
+    entry = {"name": prompt_name}
+    entry.update({key: meta[key] for key in ("description", "model", "defaults") if key in meta})
+    return entry
+
+
+def log_exchange(label: str, text: str):
+    """Log a prompt or completion at debug level when LOG_PROMPTS is enabled."""
+    if not settings.log_prompts or not logger.isEnabledFor(logging.DEBUG):
+        return
+    if settings.log_redact_pattern is not None:
+        text = settings.log_redact_pattern.sub("[REDACTED]", text)
+    limit = settings.log_prompts_max_chars
+    if limit > 0 and len(text) > limit:
+        text = text[:limit] + f"… [truncated, {len(text)} chars total]"
+    logger.debug("%s: %s", label, text)
+
+
+def parse_sse_line(line: str):
+    """Return the decoded JSON payload of an SSE `data:` line, "[DONE]", or None."""
+    line = line.strip()
+    if not line.startswith("data:"):
+        return None
+    data = line[len("data:"):].strip()
+    if data == "[DONE]":
+        return data
+    try:
+        return json.loads(data)
+    except ValueError:
+        return None
+
+
+def sse_event(payload, event: Optional[str] = None) -> bytes:
+    # Named events (event: stats) are an extension; plain OpenAI chunks have no event line
+    prefix = f"event: {event}\n" if event else ""
+    if isinstance(payload, str):
+        return f"{prefix}data: {payload}\n\n".encode()
+    return f"{prefix}data: {json.dumps(payload, ensure_ascii=False)}\n\n".encode()
+
+
+# Control tokens such as <|end|>, <|im_start|>, <|eot_id|>, <s> and </s>
+SPECIAL_TOKEN_PATTERN = re.compile(r"<\|[^|<>\s]{1,64}\|>|</?s>")
+
+
+def wants_special_tokens_stripped(request: "ChatRequest") -> bool:
+    """Strip control tokens when llama-server emits them but the request wants clean text."""
+    skip = request.skip_special_tokens if request.skip_special_tokens is not None else True
+    return skip and not settings.skip_special_tokens
+
+
+def strip_special_tokens(choices: List[dict], key: str):
+    for choice in choices:
+        message = choice.get(key) or {}
+        if message.get("content"):
+            message["content"] = SPECIAL_TOKEN_PATTERN.sub("", message["content"])
+
+
+# Additional output filters: callables returning True when a completion must be blocked
+OUTPUT_FILTERS: List[Callable[[str], bool]] = []
+
+
+def output_filtering() -> bool:
+    return settings.output_filter_pattern is not None or bool(OUTPUT_FILTERS)
+
+
+def output_blocked(text: str) -> bool:
+    if settings.output_filter_pattern and settings.output_filter_pattern.search(text):
+        return True
+    return any(blocked(text) for blocked in OUTPUT_FILTERS)
+
+
+def filtered_content(content: str) -> str:
+    """What OUTPUT_FILTER_ACTION leaves of blocked content."""
+    if settings.output_filter_action == "redact" and settings.output_filter_pattern:
+        content = settings.output_filter_pattern.sub("[REDACTED]", content)
+    # Hook filters can't say which part to redact, so their hits empty the content
+    if settings.output_filter_action == "empty" or any(blocked(content) for blocked in OUTPUT_FILTERS):
+        content = ""
+    return content
+
+
+class PostProcessor:
+    """A stage of the completion post-processing pipeline (POST_PROCESSORS).
+
+    Stages run in the configured order on each finished choice of a non-streamed
+    completion; `data` is the whole response, for stages that adjust usage.
+    """
+    name = ""
+
+    def enabled(self, request: "ChatRequest") -> bool:
+        return True
+
+    def apply(self, choice: dict, data: dict):
+        raise NotImplementedError
+
+
+class StripSpecialTokens(PostProcessor):
+    name = "strip_special_tokens"
+
+    def enabled(self, request: "ChatRequest") -> bool:
+        return wants_special_tokens_stripped(request)
+
+    def apply(self, choice: dict, data: dict):
+        strip_special_tokens([choice], "message")
+
+
+THINKING_PATTERN = re.compile(r"<think>.*?(</think>|$)\s*", re.DOTALL)
+
+
+class StripThinking(PostProcessor):
+    """Remove <think>...</think> reasoning blocks emitted by reasoning models."""
+    name = "strip_thinking"
+
+    def apply(self, choice: dict, data: dict):
+        message = choice.get("message") or {}
+        if message.get("content"):
+            message["content"] = THINKING_PATTERN.sub("", message["content"])
+
+
+class Trim(PostProcessor):
+    name = "trim"
+
+    def apply(self, choice: dict, data: dict):
+        message = choice.get("message") or {}
+        if message.get("content"):
+            message["content"] = message["content"].strip()
+
+
+class EmptyCompletion(PostProcessor):
+    """Make a model that emitted EOS straight away read as a normal, zero-token stop."""
+    name = "empty_completion"
+
+    def apply(self, choice: dict, data: dict):
+        message = choice.get("message") or {}
+        # Empty output cut off by max_tokens (e.g. only stripped special tokens) isn't an EOS
+        if message.get("content") or message.get("tool_calls") or \
+                choice.get("finish_reason") not in (None, "stop"):
+            return
+        message["content"] = ""
+        choice["finish_reason"] = "stop"
+        # An immediate EOS may be counted as one generated token; more means earlier
+        # stages (e.g. strip_thinking) emptied real output, whose tokens still count
+        counted = choice.get("completion_tokens") or 0
+        if counted > 1:
+            return
+        logger.debug("Model produced an empty completion (choice %d)", choice.get("index", 0))
+        choice["completion_tokens"] = 0
+        usage = data.get("usage")
+        if usage and counted:
+            usage["completion_tokens"] = max(0, usage.get("completion_tokens", 0) - counted)
+            usage["total_tokens"] = usage.get("prompt_tokens", 0) + usage["completion_tokens"]
+
+
+class ContentFilter(PostProcessor):
+    """Redact or empty blocked completions and mark them finish_reason content_filter."""
+    name = "content_filter"
+
+    def enabled(self, request: "ChatRequest") -> bool:
+        return output_filtering()
+
+    def apply(self, choice: dict, data: dict):
+        message = choice.get("message") or {}
+        content = message.get("content")
+        if not content or not output_blocked(content):
+            return
+        message["content"] = filtered_content(content)
+        choice["finish_reason"] = "content_filter"
+
+
+POST_PROCESSORS = {stage.name: stage for stage in (
+    StripSpecialTokens(), StripThinking(), Trim(), EmptyCompletion(), ContentFilter())}
+
+unknown_stages = [name for name in settings.post_processors if name not in POST_PROCESSORS]
+if unknown_stages:
+    raise ValueError(f"Unknown POST_PROCESSORS stages {unknown_stages}; "
+                     f"available: {', '.join(POST_PROCESSORS)}")
+
+
+def stage_active(name: str, request: "ChatRequest") -> bool:
+    # Unknown names can only come from a SIGHUP reload; they are skipped
+    return name in settings.post_processors and name in POST_PROCESSORS \
+        and POST_PROCESSORS[name].enabled(request)
+
+
+def post_process(data: dict, request: "ChatRequest"):
+    stages = [POST_PROCESSORS[name] for name in settings.post_processors if stage_active(name, request)]
+    for choice in data.get("choices", []):
+        for stage in stages:
+            stage.apply(choice, data)
+
+
+def content_delta(event: dict) -> Optional[str]:
+    """Return the content of a plain single-choice content chunk, else None."""
+    choices = event.get("choices") or []
+    if len(choices) != 1 or choices[0].get("finish_reason"):
+        return None
+    delta = choices[0].get("delta") or {}
+    if set(delta) - {"content"} or not delta.get("content"):
+        return None
+    return delta["content"]
+
+
+def merge_content_events(events: List[dict]) -> dict:
+    """Combine buffered content chunks into a single chunk."""
+    merged = events[-1]
+    merged["choices"][0]["delta"]["content"] = "".join(content_delta(e) for e in events)
+    return merged
+
+
+def usage_event(template: dict, usage: dict) -> dict:
+    """The final stream_options.include_usage chunk: no choices, full usage."""
+    add_cost(usage)
+    event = {key: template.get(key) for key in ("id", "object", "created", "model", "system_fingerprint")}
+    event.update({"choices": [], "usage": usage})
+    return event
+
+
+def finish_event(template: dict, reason: str) -> dict:
+    """A content-less chunk ending the stream's only choice with `reason`."""
+    event = {key: template.get(key) for key in ("id", "object", "created", "model", "system_fingerprint")}
+    event["choices"] = [{"index": 0, "delta": {}, "finish_reason": reason}]
+    return event
+
+
+def merge_tool_call_deltas(tool_calls: List[dict], deltas: List[dict]):
+    """Accumulate streamed `delta.tool_calls` into complete calls, keyed by their index."""
+    for delta in deltas:
+        index = delta.get("index", 0)
+        while len(tool_calls) <= index:
+            tool_calls.append({"id": None, "type": "function", "function": {"name": "", "arguments": ""}})
+        call = tool_calls[index]
+        call["id"] = delta.get("id") or call["id"]
+        function = delta.get("function") or {}
+        call["function"]["name"] += function.get("name") or ""
+        call["function"]["arguments"] += function.get("arguments") or ""
+
+
+# llama.cpp builds from before streamed tool calls reject `stream` together with `tools`
+TOOLS_STREAM_UNSUPPORTED_PATTERN = re.compile(r"tools.*stream|stream.*tools", re.IGNORECASE | re.DOTALL)
+TOOL_ARGUMENTS_CHUNK = 32
+
+
+def replayed_stream_events(data: dict) -> List[dict]:
+    """Chunks replaying a finished chat.completion as a stream, tool calls included.
+
+    Tool-call arguments are split into pieces so clients see the same delta format as
+    with a streaming upstream: name and id first, then the arguments incrementally.
+    """
+    template = {key: data.get(key) for key in ("id", "created", "model", "system_fingerprint")}
+    template["object"] = "chat.completion.chunk"
+    choice = (data.get("choices") or [{}])[0]
+    message = choice.get("message") or {}
+
+    def chunk(delta: dict, finish_reason: Optional[str] = None) -> dict:
+        return {**template, "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}]}
+
+    events = [chunk({"role": "assistant", "content": None})]
+    if message.get("content"):
+        events.append(chunk({"content": message["content"]}))
+    for index, call in enumerate(message.get("tool_calls") or []):
+        function = call.get("function") or {}
+        events.append(chunk({"tool_calls": [{"index": index, "id": call.get("id"), "type": "function",
+                                             "function": {"name": function.get("name"), "arguments": ""}}]}))
+        arguments = function.get("arguments") or ""
+        for start in range(0, len(arguments), TOOL_ARGUMENTS_CHUNK):
+            events.append(chunk({"tool_calls": [{"index": index, "function": {
+                "arguments": arguments[start:start + TOOL_ARGUMENTS_CHUNK]}}]}))
+    events.append(chunk({}, choice.get("finish_reason") or "stop"))
+    return events
+
+
+def truncate_utf8(text: str, limit: int) -> str:
+    """The longest prefix of text that encodes to at most `limit` UTF-8 bytes."""
+    return text.encode()[:limit].decode("utf-8", errors="ignore")
+
+
+class OutputCap:
+    """MAX_OUTPUT_BYTES for a stream: counts what was passed on and trims what would exceed it."""
+
+    def __init__(self, limit: int):
+        self.limit = limit
+        # Characters and bytes passed on so far
+        self.chars = self.bytes = 0
+
+    def take(self, content: str) -> str:
+        """The part of `content` that may still be passed on; shorter once the cap is hit."""
+        if not self.limit:
+            return content
+        size = len(content.encode())
+        if self.bytes + size > self.limit:
+            content = truncate_utf8(content, self.limit - self.bytes)
+            size = len(content.encode())
+        self.chars, self.bytes = self.chars + len(content), self.bytes + size
+        return content
+
+
+def stop_offset(text: str, pattern: "re.Pattern") -> Optional[int]:
+    """End of the first stop_regex match, searching only the trailing STOP_REGEX_WINDOW chars.
+
+    The window keeps each check proportional to the window rather than the whole output,
+    at the price of missing matches longer than it. It does not tame exponential
+    backtracking; validation refuses the nested quantifiers that cause it.
+    """
+    match = pattern.search(text, max(0, len(text) - settings.stop_regex_window))
+    return match.end() if match else None
+
+
+def first_literal(parsed) -> Optional[int]:
+    """The character a parsed pattern must start with, or None when it isn't a fixed one."""
+    if not parsed:
+        return None
+    op, av = parsed[0]
+    if op == sre_constants.LITERAL:
+        return av
+    if op == sre_constants.SUBPATTERN:
+        return first_literal(av[-1])
+    return None
+
+
+def has_nested_quantifier(parsed, repeated: bool = False) -> bool:
+    """Whether a parsed pattern repeats something that itself repeats, like (a+)+ or (a*b?)*,
+    or repeats alternatives that can start alike, like (a|aa)+.
+
+    Python's re backtracks exponentially on such patterns when a match fails, and a
+    search can't be interrupted once started, so they are refused outright.
+    """
+    for op, av in parsed:
+        if op == sre_constants.BRANCH and repeated:
+            firsts = [first_literal(branch) for branch in av[1]]
+            if None in firsts or len(set(firsts)) < len(firsts):
+                return True
+        if op in (sre_constants.MAX_REPEAT, sre_constants.MIN_REPEAT):
+            _, high, body = av
+            repeats = high > 1
+            if repeated and repeats:
+                return True
+            if has_nested_quantifier(body, repeated or repeats):
+                return True
+            continue
+        children = av if isinstance(av, (list, tuple)) else [av]
+        for child in children:
+            if isinstance(child, sre_parse.SubPattern) and has_nested_quantifier(child, repeated):
+                return True
+            if isinstance(child, list) and any(isinstance(branch, sre_parse.SubPattern)
+                                               and has_nested_quantifier(branch, repeated)
+                                               for branch in child):
+                return True
+    return False
+
+
+def stop_limit_problem(stops: List[str]) -> Optional[str]:
+    """Why a stop list exceeds MAX_STOP_STRINGS or MAX_STOP_TOTAL_CHARS, if it does."""
+    if len(stops) > settings.max_stop_strings:
+        return f"at most {settings.max_stop_strings} stop strings are allowed"
+    total = sum(len(stop) for stop in stops)
+    if total > settings.max_stop_total_chars:
+        return f"stop strings total {total} characters, maximum is {settings.max_stop_total_chars}"
+    return None
+
+
+def stop_segments(stop: str) -> List[Optional[str]]:
+    """Split a stop string into literal runs and whitespace runs (None).
+
+    Whitespace-only stops such as "\n\n" stay literal, so they don't match a single space.
+    """
+    if not stop.strip():
+        return [stop]
+    return [None if part.isspace() else part for part in re.split(r"(\s+)", stop) if part]
+
+
+def request_stops(request: ChatRequest) -> List[List[Optional[str]]]:
+    stops = [request.stop] if isinstance(request.stop, str) else request.stop or []
+    return [stop_segments(stop) for stop in stops]
+
+
+def match_stop(text: str, i: int, segments: List[Optional[str]], fold: bool) -> Optional[bool]:
+    """Match a stop at text[i:]: True if it matches, False if the text ends mid-match, else None."""
+    for segment in segments:
+        if segment is None:
+            if i >= len(text):
+                return False
+            if not text[i].isspace():
+                return None
+            while i < len(text) and text[i].isspace():
+                i += 1
+            continue
+        chunk = text[i:i + len(segment)]
+        if any(a != b and not (fold and a.lower() == b.lower()) for a, b in zip(chunk, segment)):
+            return None
+        if len(chunk) < len(segment):
+            return False
+        i += len(segment)
+    return True
+
+
+def find_stop(text: str, stops: List[List[Optional[str]]], fold: bool, start: int = 0) -> tuple:
+    """The earliest stop match in text[start:], as (match start or None, hold position).
+
+    Text from the hold position on might be the beginning of a stop (e.g. a trailing
+    whitespace token before "\nUser:"), so streams hold it back until that's decided.
+    """
+    hold = len(text)
+    for i in range(start, len(text)):
+        for segments in stops:
+            matched = match_stop(text, i, segments, fold)
+            if matched:
+                return i, i
+            if matched is False:
+                hold = min(hold, i)
+    return None, hold
+
+
+# Per-worker totals for the summary line logged on shutdown
+completion_stats = {"requests": 0, "latency_ms": 0}
+
+
+def log_completion(model: str, usage: Optional[dict], latency_ms: int, stream: bool = False,
+                   sampled: bool = True) -> bool:
+    """Log a finished chat completion: debug when sampled, warning (and counted) when slow.
+
+    Returns whether it was slow.
+    """
+    completion_stats["requests"] += 1
+    completion_stats["latency_ms"] += latency_ms
+    slow = 0 < settings.slow_request_ms <= latency_ms
+    if slow:
+        SLOW_REQUESTS.labels(model=model).inc()
+    if slow or sampled:
+        usage = usage or {}
+        logger.log(logging.WARNING if slow else logging.DEBUG,
+                   "%s: model=%s stream=%s prompt_tokens=%s completion_tokens=%s latency_ms=%d",
+                   "Slow chat completion" if slow else "Chat completion done", model, stream,
+                   usage.get("prompt_tokens", "?"), usage.get("completion_tokens", "?"), latency_ms)
+    return slow
+
+
+class RequestLog:
+    """Logging for one chat completion under LOG_SAMPLE_RATE.
+
+    A sampled request logs its prompt and completion as they happen. Otherwise
+    they are held back and only logged if the request turns out slow or fails.
+    """
+
+    def __init__(self, model: str, stream: bool = False):
+        self.model, self.stream = model, stream
+        self.sampled = random.random() < settings.log_sample_rate
+        self.held = []
+
+    def exchange(self, label: str, text: str):
+        if self.sampled:
+            log_exchange(label, text)
+        elif settings.log_prompts:
+            self.held.append((label, text))
+
+    def flush(self):
+        for label, text in self.held:
+            log_exchange(label, text)
+        self.held = []
+
+    def done(self, usage: Optional[dict], latency_ms: int):
+        if log_completion(self.model, usage, latency_ms, self.stream, self.sampled):
+            self.flush()
+
+    def failed(self, status: Optional[int], detail, latency_ms: int):
+        # Client errors are routine; upstream and server errors are what operators look for
+        level = logging.INFO if status is not None and status < 500 else logging.WARNING
+        logger.log(level, "Chat completion failed: model=%s stream=%s status=%s latency_ms=%d error=%s",
+                   self.model, self.stream, status or "-", latency_ms, detail)
+        self.flush()
+
+
+async def stream_usage(base_url: str, messages: Union[List[dict], str], completion: str) -> dict:
+    """Count usage ourselves when llama-server didn't report it on the stream."""
+    prompt_tokens = await count_prompt_tokens(base_url, messages)
+    completion_tokens = len(await tokenize(base_url, completion))
+    return {"prompt_tokens": prompt_tokens, "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens}
+
+
+async def partial_usage(base_url: str, messages: Union[List[dict], str],
+                        completion: str = "") -> Optional[dict]:
+    """Usage of a request that failed after reaching llama.cpp, for cost accounting.
+
+    Prompt processing costs real compute even when generation fails. Best effort:
+    None when the upstream can't tokenize either.
+    """
+    try:
+        usage = await stream_usage(base_url, messages, completion)
+    except (httpx.HTTPError, ValueError):
+        return None
+    add_cost(usage)
+    return usage
+
+
+# Output that wasn't valid UTF-8: lone surrogates, both from JSON escapes such as
+# \ud83d and from undecodable bytes, which upstream_json and upstream_lines decode
+# with surrogateescape. A U+FFFD the model wrote is a valid character and kept.
+INVALID_UTF8_PATTERN = re.compile("[\ud800-\udfff]")
+
+
+def sanitize_output_encoding(data: dict):
+    """Make every choice's text valid UTF-8 per OUTPUT_ENCODING_POLICY, logging when it wasn't."""
+    replacement = "\ufffd" if settings.output_encoding_policy == "replace" else ""
+    for choice in data.get("choices") or []:
+        for container, key in ((choice.get("message"), "content"), (choice.get("delta"), "content"),
+                               (choice, "text")):
+            text = (container or {}).get(key)
+            if not isinstance(text, str) or not INVALID_UTF8_PATTERN.search(text):
+                continue
+            logger.warning("Model output had %d invalid UTF-8 sequence(s); applying policy %s",
+                           len(INVALID_UTF8_PATTERN.findall(text)), settings.output_encoding_policy)
+            container[key] = INVALID_UTF8_PATTERN.sub(replacement, text)
+
+
+def normalize_response(data: dict, object_type: str):
+    """Apply the configured id prefix, make `object` match the endpoint, and fix the text encoding."""
+    upstream_id = str(data.get("id") or "")
+    suffix = upstream_id.split("-", 1)[1] if "-" in upstream_id else upstream_id
+    data["id"] = f"{settings.response_id_prefix}{suffix or int(time.time() * 1000)}"
+    data["object"] = object_type
+    sanitize_output_encoding(data)
+
+
+# Error messages llama.cpp emits when it cannot allocate a context/KV cache
+MEMORY_ERROR_PATTERN = re.compile(
+    r"out of memory|failed to allocate|cannot allocate|not enough space|alloc(ation)? failed",
+    re.IGNORECASE,
+)
+memory_pressure_until = 0.0
+
+
+def is_memory_error(text: str) -> bool:
+    return bool(MEMORY_ERROR_PATTERN.search(text))
+
+
+def memory_pressure_error() -> HTTPException:
+    global memory_pressure_until
+    memory_pressure_until = time.time() + settings.oom_retry_after
+    logger.warning("Upstream memory pressure: model could not allocate a session, "
+                   "refusing new requests for %ss", settings.oom_retry_after)
+    return HTTPException(status_code=503,
+                         detail="Model is temporarily out of memory, retry shortly",
+                         headers={"Retry-After": str(settings.oom_retry_after)})
+
+
+def upstream_unavailable_error(status_code: int, text: str) -> Optional[HTTPException]:
+    """The 503 for an upstream failure that is worth retrying later, or None.
+
+    Only allocation failures start the OOM back-off. llama-server also answers 503 while
+    the model loads or when every slot is busy; those pass through without refusing
+    anyone else.
+    """
+    if is_memory_error(text):
+        return memory_pressure_error()
+    if status_code != 503:
+        return None
+    logger.info("Upstream unavailable (503): %s", text[:200])
+    return HTTPException(status_code=503, detail=f"Model server unavailable, retry shortly: {text[:200]}",
+                         headers={"Retry-After": "1"})
+
+
+def is_transient_error(e: httpx.HTTPError) -> bool:
+    """Connection hiccups and allocation failures may succeed on retry; bad requests won't."""
+    if isinstance(e, httpx.HTTPStatusError):
+        return e.response.status_code == 503 or is_memory_error(e.response.text)
+    return isinstance(e, (httpx.ConnectError, httpx.ReadError, httpx.RemoteProtocolError))
+
+
+async def with_upstream_retries(call):
+    """Await call(), retrying transient upstream failures with exponential backoff."""
+    for attempt in range(settings.upstream_retries + 1):
+        try:
+            return await call()
+        except httpx.HTTPError as e:
+            if attempt >= settings.upstream_retries or not is_transient_error(e):
+                raise
+            delay = settings.upstream_retry_backoff * 2 ** attempt
+            logger.warning("Transient upstream failure (%s), retrying in %.2fs (attempt %d of %d)",
+                           e, delay, attempt + 1, settings.upstream_retries)
+            await asyncio.sleep(delay)
+
+
+def check_memory_pressure():
+    """Reject requests while we are backing off after an upstream OOM."""
+    remaining = memory_pressure_until - time.time()
+    if remaining > 0:
+        raise HTTPException(status_code=503,
+                            detail="Model is temporarily out of memory, retry shortly",
+                            headers={"Retry-After": str(max(1, int(remaining)))})
+
+
+def add_cost(usage: Optional[dict]):
+    """Attach an estimated cost to an upstream usage object when pricing is configured."""
+    if not usage:
+        return
+    if settings.price_per_1k_prompt is None and settings.price_per_1k_completion is None:
+        return
+    cost = (usage.get("prompt_tokens", 0) * (settings.price_per_1k_prompt or 0.0)
+            + usage.get("completion_tokens", 0) * (settings.price_per_1k_completion or 0.0)) / 1000
+    usage["cost"] = round(cost, 8)
+
+
+def resolve_model(model: str) -> str:
+    return settings.model_aliases.get(model, model)
+
+
+def upstream_url(model: str) -> str:
+    """Resolve the llama-server base URL that serves the requested model (or alias)."""
+    if not settings.models:
+        return f"http://localhost:{UPSTREAM_BASE_PORT}"
+    entry = settings.models.get(resolve_model(model))
+    if entry is None:
+        raise HTTPException(status_code=404, detail=f"Model {model} not found")
+    return entry["url"]
+
+
+async def upstream_props(base_url: str) -> dict:
+    """Fetch (and cache) llama-server's /props, which reports the loaded context size."""
+    cached = upstream_props.cache.get(base_url)
+    if cached is None:
+        resp = await http_client.get(f"{base_url}/props", timeout=5.0)
+        resp.raise_for_status()
+        cached = upstream_props.cache[base_url] = resp.json()
+    return cached
+
+upstream_props.cache = {}
+
+
+async def apply_chat_template(base_url: str, messages: List[dict]) -> str:
+    """The prompt text llama-server builds from messages with the model's chat template."""
+    resp = await http_client.post(f"{base_url}/apply-template", json={"messages": messages})
+    resp.raise_for_status()
+    return resp.json()["prompt"]
+
+
+def model_path(model: str) -> str:
+    """The GGUF file serving a model, resolved the way entrypoint.sh does."""
+    entry = settings.models.get(resolve_model(model))
+    if entry is None:
+        if os.getenv("MODEL_FILE"):
+            return f"/models/{os.environ['MODEL_FILE']}"
+        return os.getenv("MODEL_PATH", "/models/model.gguf")
+    return entry["path"] if entry["path"].startswith("/") else f"/models/{entry['path']}"
+
+
+@lru_cache(maxsize=32)
+def gguf_has_chat_template(path: str) -> bool:
+    # Same check as entrypoint.sh: GGUF metadata sits in the first few MB
+    try:
+        with open(path, 'rb') as f:
+            return b"tokenizer.chat_template" in f.read(32 * 1024 * 1024)
+    except OSError:
+        return False
+
+
+def chat_template_name(model: str) -> str:
+    """Which chat template entrypoint.sh started the model with, for ?debug_prompt=true.
+
+    Per-model template from MODELS, then the GGUF's embedded one, then the global default.
+    """
+    entry = settings.models.get(resolve_model(model)) or {}
+    if entry.get("template"):
+        return entry["template"]
+    if gguf_has_chat_template(model_path(model)):
+        return "gguf-embedded"
+    if os.getenv("CHAT_TEMPLATE_STRING"):
+        return "custom (CHAT_TEMPLATE_STRING)"
+    return os.getenv("CHAT_TEMPLATE") or "llama.cpp default"
+
+
+# Models already warned about falling back to llama.cpp's default template, this worker only
+default_template_warned = set()
+
+
+def check_chat_template(model: str):
+    """Refuse or warn about chat requests for a model with no chat template of its own.
+
+    Base models and some GGUFs carry none, and llama.cpp's default (chatml) is likely
+    the wrong format for them.
+    """
+    if MOCK_MODEL or chat_template_name(model) != "llama.cpp default":
+        return
+    name = resolve_model(model)
+    if settings.require_chat_template:
+        raise HTTPException(status_code=400,
+                            detail=f"Model {name} has no chat template; the operator must set CHAT_TEMPLATE, "
+                                   f"CHAT_TEMPLATE_STRING or a :template suffix in MODELS")
+    if name not in default_template_warned:
+        default_template_warned.add(name)
+        logger.warning("Model %s has no chat template, formatting chats with llama.cpp's default (chatml); "
+                       "set CHAT_TEMPLATE if that is not the model's format", name)
+
+
+async def tokenize(base_url: str, text: str, add_special: bool = False) -> List[int]:
+    """Token ids of text; `add_special` adds BOS and the like, as for a real prompt."""
+    resp = await http_client.post(f"{base_url}/tokenize", json={"content": text, "add_special": add_special})
+    resp.raise_for_status()
+    return resp.json().get("tokens", [])
+
+
+async def count_prompt_tokens(base_url: str, messages: Union[List[dict], str]) -> int:
+    """Tokenize messages exactly as llama-server will format them for the model.
+
+    A string is a legacy completion's raw prompt, which is sent without a template.
+    """
+    if isinstance(messages, str):
+        return len(await tokenize(base_url, messages))
+    return len(await tokenize(base_url, await apply_chat_template(base_url, messages)))
+
+
+async def truncate_history(request: ChatRequest, base_url: str, payload: dict) -> Optional[dict]:
+    """Drop the oldest non-system messages until the prompt (plus max_tokens) fits the context.
+
+    Returns what was dropped, or None when nothing had to be removed.
+    """
+    if not settings.auto_truncate:
+        return None
+    context = request.n_ctx or (await upstream_props(base_url)).get(
+        "default_generation_settings", {}).get("n_ctx")
+    if not context:
+        return None
+    # With context shifting the output can roll past the context; only the prompt must fit
+    reserved = 0 if settings.context_shift else payload.get("max_tokens") or 0
+    messages = payload["messages"]
+    original_tokens = prompt_tokens = await count_prompt_tokens(base_url, messages)
+    dropped = 0
+    while prompt_tokens + reserved > context:
+        # Keep system messages and the latest message; drop from the oldest turn
+        droppable = [i for i, m in enumerate(messages[:-1]) if m.get("role") != "system"]
+        if not droppable:
+            break
+        del messages[droppable[0]]
+        dropped += 1
+        # Don't leave the history starting on an orphaned assistant/tool turn
+        while True:
+            rest = [i for i, m in enumerate(messages[:-1]) if m.get("role") != "system"]
+            if not rest or messages[rest[0]].get("role") == "user":
+                break
+            del messages[rest[0]]
+            dropped += 1
+        prompt_tokens = await count_prompt_tokens(base_url, messages)
+    if not dropped:
+        return None
+    logger.info("Truncated %d messages (%d tokens) to fit context of %d",
+                dropped, original_tokens - prompt_tokens, context)
+    return {"messages_dropped": dropped, "tokens_dropped": original_tokens - prompt_tokens}
+
+
+def truncation_headers(truncation: Optional[dict]) -> dict:
+    if not truncation:
+        return {}
+    return {"X-Truncated-Messages": str(truncation["messages_dropped"]),
+            "X-Truncated-Tokens": str(truncation["tokens_dropped"])}
+
+
+def adjustment_warnings(request: ChatRequest, payload: dict, truncation: Optional[dict]) -> List[str]:
+    """Describe what the server changed about a request, for ?warnings=true."""
+    warnings = []
+    if truncation:
+        warnings.append(f"conversation truncated: dropped {truncation['messages_dropped']} messages "
+                        f"({truncation['tokens_dropped']} tokens) to fit the context")
+    if request.max_tokens is not None and payload.get("max_tokens", request.max_tokens) < request.max_tokens:
+        warnings.append(f"max_tokens reduced from {request.max_tokens} to {payload['max_tokens']}")
+    if request.temperature and payload.get("temperature") == 0:
+        warnings.append(f"temperature {request.temperature:g} is below TEMPERATURE_FLOOR "
+                        f"({settings.temperature_floor:g}); greedy decoding used")
+    ignored = [field for field in GREEDY_IGNORED_SAMPLERS
+               if getattr(request, field, None) is not None and field not in payload]
+    if ignored and payload.get("temperature") == 0:
+        warnings.append(f"{', '.join(ignored)} ignored: temperature 0 decodes greedily")
+    return warnings
+
+
+async def apply_context_budget(request: ChatRequest, base_url: str, payload: dict):
+    """Enforce a per-request n_ctx, capping max_tokens so prompt + output fit in it.
+
+    llama-server allocates its context when the model is loaded, so this bounds what a
+    request may use rather than resizing the KV cache.
+    """
+    if request.n_ctx is None:
+        return
+    model_ctx = (await upstream_props(base_url)).get("default_generation_settings", {}).get("n_ctx")
+    if model_ctx and request.n_ctx > model_ctx:
+        raise HTTPException(status_code=400,
+                            detail=f"n_ctx {request.n_ctx} exceeds the model context of {model_ctx}")
+    prompt_tokens = await count_prompt_tokens(base_url, payload["messages"])
+    if prompt_tokens >= request.n_ctx:
+        raise HTTPException(status_code=400,
+                            detail=f"Prompt is {prompt_tokens} tokens, which does not fit in n_ctx {request.n_ctx}")
+    available = request.n_ctx - prompt_tokens
+    if payload.get("max_tokens") is None or payload["max_tokens"] > available:
+        payload["max_tokens"] = available
+
+
+async def context_stats(request: ChatRequest, base_url: str, data: dict) -> dict:
+    """How much of the context window the request took: its prompt plus the longest choice."""
+    usage = data.get("usage") or {}
+    completion = max((choice.get("completion_tokens") or 0 for choice in data.get("choices", [])),
+                     default=0) or usage.get("completion_tokens", 0)
+    size = request.n_ctx or (await upstream_props(base_url)).get("default_generation_settings", {}).get("n_ctx")
+    return {"context_used_tokens": usage.get("prompt_tokens", 0) + completion, "context_size": size}
+
+
+async def check_prompt_size(base_url: str, payload: dict):
+    """Reject prompts over MAX_PROMPT_TOKENS, even when they would fit the context."""
+    if settings.max_prompt_tokens <= 0:
+        return
+    prompt_tokens = await count_prompt_tokens(base_url, payload["messages"] if "messages" in payload
+                                              else payload["prompt"])
+    if prompt_tokens > settings.max_prompt_tokens:
+        raise HTTPException(status_code=400,
+                            detail=f"Prompt is {prompt_tokens} tokens, maximum is {settings.max_prompt_tokens}")
+
+
+in_flight_requests = 0
+# Chat and completion requests running in this worker, for POST /admin/abort-all
+active_inferences = set()
+
+
+class ActiveInference:
+    """A registered inference; abort() cancels its task, while streams poll `aborted`."""
+
+    def __init__(self, task: Optional[asyncio.Task] = None):
+        self.task, self.aborted = task, False
+        active_inferences.add(self)
+
+    def abort(self):
+        self.aborted = True
+        if self.task is not None:
+            self.task.cancel()
+
+    def aborted_error(self) -> HTTPException:
+        """The 503 an aborted request ends with, once its cancellation has been absorbed."""
+        if self.task is not None and hasattr(self.task, "uncancel"):
+            self.task.uncancel()
+        return HTTPException(status_code=503, detail="Request aborted by the operator")
+
+    def done(self):
+        active_inferences.discard(self)
+
+
+def abort_all_inferences() -> int:
+    """Abort every inference in this worker (also the SIGUSR2 handler); returns how many."""
+    inferences = list(active_inferences)
+    for inference in inferences:
+        inference.abort()
+    logger.warning("Aborted %d in-flight requests", len(inferences))
+    return len(inferences)
+
+
+# Permits handed out and not yet released, this worker only
+held_permits = 0
+# Streams holding a MAX_CONCURRENT_STREAMS slot, this worker only
+open_streams = 0
+
 
-# Instrument metrics at import time so middleware is registered before Uvicorn
-Instrumentator().instrument(app).expose(app)
+def load_headers() -> dict:
+    """Current load as plain counter reads; the event loop is single-threaded, so no locking."""
+    if not settings.load_headers:
+        return {}
+    headers = {"X-Inflight-Requests": str(held_permits)}
+    if request_queue is not None and settings.max_concurrent_requests > 0:
+        headers["X-Available-Permits"] = str(request_queue.available)
+    return headers
 
-# Add shutdown event to close client
-@app.on_event("shutdown")
-async def shutdown_event():
-    await http_client.aclose()
 
+class Permit:
+    """A held inference slot; release() is idempotent."""
 
-class Message(BaseModel):
-    role: str
-    content: str
+    def __init__(self, queue: Optional["RequestQueue"], wait_ms: int, position: int):
+        global held_permits
+        self.queue, self.wait_ms, self.position = queue, wait_ms, position
+        self.held, self.stream = True, False
+        held_permits += 1
 
-class ChatRequest(BaseModel):
-    model: str
-    messages: List[Message]
-    temperature: Optional[float] = 0.7
-    max_tokens: Optional[int] = 100
+    def headers(self) -> dict:
+        headers = load_headers()
+        if self.position:
+            headers.update({"X-Queue-Wait-Ms": str(self.wait_ms), "X-Queue-Position": str(self.position)})
+        return headers
 
-    class Config:
-        # Optimize validation
-        validate_assignment = True
-        extra = "forbid"
+    def release(self):
+        global held_permits, open_streams
+        if self.held:
+            self.held = False
+            held_permits -= 1
+            if self.stream:
+                open_streams -= 1
+        if self.queue is not None:
+            self.queue.release()
+            self.queue = None
 
-@lru_cache(maxsize=32)
-def read_prompt_file(prompt_name: str) -> str:
-    prompt_path = f"/prompts/{prompt_name}.txt"
-    if not os.path.exists(prompt_path):
-        raise HTTPException(status_code=404, detail=f"Prompt template {prompt_name} not found")
-    with open(prompt_path, 'r') as f:
-        return f.read()
+
+# Request priority tiers; freed slots go to the best-ranked waiter, FIFO within a tier
+PRIORITY_RANKS = {"high": 0, "normal": 1, "low": 2}
+
+
+class RequestQueue:
+    """Concurrency limit with a bounded priority queue: wait up to max_wait, or 503 when full."""
+
+    def __init__(self, concurrency: int):
+        self.concurrency = concurrency
+        self.available = concurrency
+        # Heap of (rank, arrival, future); futures of timed-out waiters are skipped
+        self.waiters = []
+        self.arrivals = itertools.count()
+        self.waiting = 0
+
+    def reject(self, reason: str) -> HTTPException:
+        retry_after = max(1, int(settings.queue_max_wait))
+        logger.warning("Rejecting request: %s", reason)
+        return HTTPException(status_code=503, detail=f"Server busy: {reason}",
+                             headers={"Retry-After": str(retry_after), **load_headers()})
+
+    def release(self):
+        """Hand the freed slot to the best-ranked live waiter, or return it to the pool."""
+        while self.waiters:
+            _, _, future = heapq.heappop(self.waiters)
+            if not future.done():
+                future.set_result(None)
+                return
+        self.available += 1
+
+    async def acquire(self, priority: str = "normal") -> Permit:
+        if self.available > 0:
+            self.available -= 1
+            return Permit(self, 0, 0)
+        if self.waiting >= settings.queue_max_depth:
+            raise self.reject(f"queue is full ({self.waiting} waiting)")
+        rank = PRIORITY_RANKS.get(priority, PRIORITY_RANKS["normal"])
+        entry = (rank, next(self.arrivals), asyncio.get_running_loop().create_future())
+        heapq.heappush(self.waiters, entry)
+        # Position among live waiters, counting only those ahead of this one
+        position = 1 + sum(1 for waiter in self.waiters if waiter < entry and not waiter[2].done())
+        self.waiting += 1
+        started = time.time()
+        future = entry[2]
+        try:
+            await asyncio.wait_for(future, timeout=settings.queue_max_wait)
+        except asyncio.TimeoutError:
+            raise self.reject(f"waited over {settings.queue_max_wait:g}s in queue")
+        except asyncio.CancelledError:
+            # A slot handed over just as the client went away must not be lost
+            if future.done() and not future.cancelled():
+                self.release()
+            raise
+        finally:
+            self.waiting -= 1
+        return Permit(self, int((time.time() - started) * 1000), position)
+
+
+request_queue = None
+
+
+def reserve_stream():
+    """Take a MAX_CONCURRENT_STREAMS slot, or refuse the stream with 503 right away."""
+    global open_streams
+    limit = settings.max_concurrent_streams
+    if 0 < limit <= open_streams:
+        logger.warning("Rejecting stream: %d streams open, limit is %d", open_streams, limit)
+        raise HTTPException(status_code=503, detail=f"Server busy: {open_streams} streams open",
+                            headers={"Retry-After": str(settings.stream_retry_after), **load_headers()})
+    open_streams += 1
+
+
+async def acquire_permit(priority: Optional[str] = None, stream: bool = False) -> Permit:
+    """Admit a request; streams must also get a stream slot, taken before queueing for a permit."""
+    global request_queue, open_streams
+    if stream:
+        reserve_stream()
+    try:
+        if settings.max_concurrent_requests <= 0:
+            permit = Permit(None, 0, 0)
+        else:
+            # Rebuilt when a SIGHUP reload changes the limit; held permits release their own queue
+            if request_queue is None or request_queue.concurrency != settings.max_concurrent_requests:
+                request_queue = RequestQueue(settings.max_concurrent_requests)
+            permit = await request_queue.acquire(priority or "normal")
+    except BaseException:
+        if stream:
+            open_streams -= 1
+        raise
+    permit.stream = stream
+    return permit
+
+
+def apply_adaptive_max_tokens(payload: dict, pending: int = 0) -> Optional[int]:
+    """Scale max_tokens down (to a floor) as in-flight requests exceed the threshold.
+
+    `pending` counts requests not yet reflected in in_flight_requests (streams only
+    register once their body starts). Returns the applied cap, or None when the
+    request keeps its full max_tokens.
+    """
+    threshold = max(1, settings.adaptive_max_tokens_threshold)
+    load = in_flight_requests + pending
+    if not settings.adaptive_max_tokens or load <= threshold:
+        return None
+    requested = payload.get("max_tokens")
+    if requested is None:
+        return None
+    cap = max(settings.adaptive_max_tokens_floor, requested * threshold // load)
+    if cap >= requested:
+        return None
+    payload["max_tokens"] = cap
+    return cap
+
+
+class RequestProblems(HTTPException):
+    """Every field-level problem found in a request, reported together in `details`."""
+
+    def __init__(self, problems: List[dict]):
+        # A lone unknown model keeps its 404; anything else is a bad request
+        status_code = 404 if [p["field"] for p in problems] == ["model"] else 400
+        summary = "; ".join(f"{p['field']}: {p['message']}" for p in problems)
+        plural = "s" if len(problems) != 1 else ""
+        super().__init__(status_code=status_code,
+                         detail=f"{len(problems)} invalid field{plural}: {summary}")
+        self.problems = problems
+
+
+@app.exception_handler(RequestProblems)
+async def request_problems_handler(request: Request, exc: RequestProblems):
+    return JSONResponse(status_code=exc.status_code,
+                        content={"detail": exc.detail, "details": exc.problems})
+
+
+class UpstreamFailure(HTTPException):
+    """An upstream error after the prompt reached llama.cpp, carrying the usage incurred."""
+
+    def __init__(self, status_code: int, detail: str, usage: Optional[dict]):
+        super().__init__(status_code=status_code, detail=detail)
+        self.usage = usage
+
+
+@app.exception_handler(UpstreamFailure)
+async def upstream_failure_handler(request: Request, exc: UpstreamFailure):
+    content = {"detail": exc.detail}
+    if exc.usage is not None:
+        content["usage"] = exc.usage
+    return JSONResponse(status_code=exc.status_code, content=content)
+
+
+def validate_chat_request(request: ChatRequest, stream: bool = False):
+    """Check the request's fields, raising RequestProblems listing everything wrong."""
+    problems = []
+
+    def problem(field: str, message: str):
+        problems.append({"field": field, "message": message})
+
+    model_known = not settings.models or resolve_model(request.model) in settings.models
+    if not model_known:
+        problem("model", f"Model {request.model} not found")
+    if request.temperature is not None and request.temperature < 0:
+        problem("temperature", "must not be negative")
+    # llama.cpp seeds are uint32, with 0xFFFFFFFF meaning "random"
+    if request.seed is not None and not 0 <= request.seed < 0xFFFFFFFF:
+        problem("seed", f"must be between 0 and {0xFFFFFFFF - 1}")
+    if request.max_tokens is not None:
+        # The loaded context is known once /props has been fetched for this upstream
+        props = upstream_props.cache.get(upstream_url(request.model)) if model_known else None
+        model_ctx = (props or {}).get("default_generation_settings", {}).get("n_ctx")
+        if request.max_tokens < 1:
+            problem("max_tokens", "must be at least 1")
+        elif model_ctx and request.n_ctx is None and not settings.context_shift \
+                and request.max_tokens > model_ctx:
+            problem("max_tokens", f"{request.max_tokens} exceeds the model context of {model_ctx}")
+    if request.n_ctx is not None and request.n_ctx <= 0:
+        problem("n_ctx", "must be positive")
+    if request.mirostat is not None and request.mirostat not in (0, 1, 2):
+        problem("mirostat", "must be 0 (off), 1 or 2")
+    if request.mirostat_tau is not None and request.mirostat_tau <= 0:
+        problem("mirostat_tau", "must be positive")
+    if request.mirostat_eta is not None and request.mirostat_eta <= 0:
+        problem("mirostat_eta", "must be positive")
+    for field in ("typical_p", "tfs_z"):
+        value = getattr(request, field)
+        if value is not None and not 0 < value <= 1:
+            problem(field, "must be greater than 0 and at most 1 (1 disables it)")
+    if request.skip_special_tokens is False and settings.skip_special_tokens:
+        # llama.cpp already left the control tokens out, so there is nothing to keep
+        problem("skip_special_tokens", "false needs the server started with SKIP_SPECIAL_TOKENS=false")
+    if request.stream_chunk_tokens is not None and request.stream_chunk_tokens < 1:
+        problem("stream_chunk_tokens", "must be at least 1")
+    n, best_of = request.n or 1, request.best_of or request.n or 1
+    model = resolve_model(request.model)
+    max_best_of = settings.model_max_best_of.get(model, settings.max_best_of)
+    max_n = min(settings.model_max_n.get(model, max_best_of), max_best_of)
+    if n < 1:
+        problem("n", "must be at least 1")
+    elif n > max_n:
+        problem("n", f"must be at most {max_n} for model {request.model}")
+    elif best_of < n:
+        problem("best_of", f"must be at least n ({n})")
+    elif best_of > max_best_of:
+        problem("best_of" if request.best_of else "n", f"must be at most {max_best_of} for model {request.model}")
+    elif stream and best_of > 1:
+        problem("best_of" if request.best_of else "n", "is not supported on streams")
+    if request.priority is not None and request.priority not in PRIORITY_RANKS:
+        problem("priority", f"must be one of {', '.join(PRIORITY_RANKS)}")
+    if request.conversation_id and not settings.conversations:
+        problem("conversation_id", "conversations are disabled; the operator must set CONVERSATIONS=true")
+    stops = [request.stop] if isinstance(request.stop, str) else request.stop or []
+    if stop_limit_problem(stops):
+        problem("stop", stop_limit_problem(stops))
+    elif any(not stop for stop in stops):
+        problem("stop", "stop strings must not be empty")
+    elif stops and request.stop_regex is not None:
+        problem("stop_regex", "cannot be combined with stop; include the stop strings in the pattern")
+    if request.stop_regex is not None:
+        if len(request.stop_regex) > settings.stop_regex_max_length:
+            problem("stop_regex", f"must be at most {settings.stop_regex_max_length} characters")
+        else:
+            try:
+                re.compile(request.stop_regex)
+            except re.error as e:
+                problem("stop_regex", f"invalid pattern: {e}")
+            else:
+                if has_nested_quantifier(sre_parse.parse(request.stop_regex)):
+                    problem("stop_regex", "nested quantifiers such as (a+)+ or (a|aa)+ are not "
+                                          "allowed (they can backtrack exponentially)")
+        if best_of > 1:
+            problem("stop_regex", "is not supported with n or best_of")
+    if request.ngram_repeat_limit is not None and request.ngram_repeat_limit < 0:
+        problem("ngram_repeat_limit", "must not be negative")
+    elif request.ngram_repeat_limit and best_of > 1:
+        problem("ngram_repeat_limit", "is not supported with n or best_of")
+    if request.ngram_size is not None and not 1 <= request.ngram_size <= 64:
+        problem("ngram_size", "must be between 1 and 64")
+    if problems:
+        raise RequestProblems(problems)
+
+
+async def check_sampler_support(request: ChatRequest, base_url: str):
+    """Reject samplers the llama.cpp build lacks instead of letting it ignore them silently.
+
+    Support is read from the sampler settings in /props; llama.cpp dropped tfs_z in late 2024.
+    """
+    wanted = [field for field in ("typical_p", "tfs_z") if getattr(request, field) is not None]
+    if not wanted:
+        return
+    try:
+        props = await upstream_props(base_url)
+    except httpx.HTTPError:
+        # Unreachable upstreams fail on the completion itself with a clearer error
+        return
+    defaults = props.get("default_generation_settings") or {}
+    params = defaults.get("params", defaults)
+    missing = [field for field in wanted if field not in params]
+    if missing:
+        raise RequestProblems([{"field": field, "message": "is not supported by this llama.cpp build"}
+                               for field in missing])
+
+
+# Server-side sampler defaults reported by llama-server that affect output
+FINGERPRINT_SAMPLER_KEYS = ("temperature", "top_k", "top_p", "min_p", "repeat_penalty",
+                            "repeat_last_n", "mirostat", "mirostat_tau", "mirostat_eta")
+QUANTIZATION_PATTERN = re.compile(r"(I?Q\d[_A-Z0-9]*|BF16|F16|F32)", re.IGNORECASE)
+
+
+async def system_fingerprint(base_url: str) -> Optional[str]:
+    """Identify the serving backend: model file, quantization, versions and sampler defaults.
+
+    Changes whenever any of these change, so clients can detect backend swaps.
+    """
+    try:
+        props = await upstream_props(base_url)
+    except (httpx.HTTPError, ValueError):
+        return None
+    model_path = props.get("model_path", "")
+    quantization = QUANTIZATION_PATTERN.findall(os.path.basename(model_path))
+    generation = props.get("default_generation_settings", {})
+    sampler = generation.get("params", generation)
+    identity = {
+        "model": model_path,
+        "quantization": quantization[-1].upper() if quantization else None,
+        "llama_cpp": props.get("build_info"),
+        "server": __version__,
+        "sampler": {k: sampler.get(k) for k in FINGERPRINT_SAMPLER_KEYS},
+    }
+    # Env sampler defaults change outputs too; left out when unset so fingerprints stay stable
+    if any(value is not None for value in settings.default_sampler.values()):
+        identity["sampler_defaults"] = settings.default_sampler
+    digest = hashlib.sha256(json.dumps(identity, sort_keys=True).encode()).hexdigest()
+    return f"fp_{digest[:12]}"
+
+
+async def upstream_completion(base_url: str, payload: dict) -> dict:
+    # Use global client instead of creating a new one each time
+    resp = await http_client.post(f"{base_url}/v1/chat/completions",
+                                json=payload,
+                                headers={"Content-Type": "application/json"})
+    resp.raise_for_status()
+    return upstream_json(resp)
+
+
+def upstream_json(resp: httpx.Response) -> dict:
+    """Decode a completion body; bytes that aren't UTF-8 become lone surrogates instead of failing."""
+    return json.loads(resp.content.decode("utf-8", errors="surrogateescape"))
+
+
+async def upstream_lines(response: httpx.Response):
+    """The lines of a streamed upstream body, decoded like upstream_json.
+
+    httpx's aiter_lines() would turn bad bytes into U+FFFD, indistinguishable from
+    one the model meant to write.
+    """
+    decoder = codecs.getincrementaldecoder("utf-8")(errors="surrogateescape")
+    pending = ""
+    async for chunk in response.aiter_bytes():
+        *lines, pending = (pending + decoder.decode(chunk)).split("\n")
+        for line in lines:
+            yield line.rstrip("\r")
+    pending += decoder.decode(b"", final=True)
+    if pending:
+        yield pending.rstrip("\r")
+
+
+def cached_prompt_tokens(usage: Optional[dict], timings: Optional[dict]) -> Optional[int]:
+    """Prompt tokens llama.cpp took from its prompt cache instead of evaluating.
+
+    Recent builds report cache_n; older ones only prompt_n, the tokens evaluated.
+    """
+    timings = timings or {}
+    if "cache_n" in timings:
+        return timings["cache_n"]
+    prompt_tokens = (usage or {}).get("prompt_tokens")
+    if prompt_tokens is None or "prompt_n" not in timings:
+        return None
+    return max(0, prompt_tokens - timings["prompt_n"])
+
+
+def add_cached_tokens(usage: Optional[dict], timings: Optional[dict]):
+    """Report prompt-cache reuse as OpenAI's usage.prompt_tokens_details.cached_tokens."""
+    cached = cached_prompt_tokens(usage, timings)
+    if usage is not None and cached is not None:
+        usage.setdefault("prompt_tokens_details", {})["cached_tokens"] = cached
+
+
+async def system_prompt_cache_result(model: str, base_url: str, payload: dict,
+                                     usage: Optional[dict], timings: Optional[dict]) -> Optional[str]:
+    """"hit" when llama.cpp reused the whole system prompt from its cache, else "miss".
+
+    None (and nothing counted) when the prompt wasn't cached, without a system prompt
+    or when llama.cpp reported no timings. The system prompt is measured as the model
+    sees it: BOS and the chat template's markup included, as rendered in this request's
+    prompt. Its token count is looked up once per distinct rendering.
+    """
+    messages = payload["messages"]
+    if not payload.get("cache_prompt") or not messages or messages[0].get("role") != "system":
+        return None
+    cached = cached_prompt_tokens(usage, timings)
+    if cached is None:
+        return None
+    lengths = system_prompt_cache_result.lengths
+    try:
+        system_only = await apply_chat_template(base_url, messages[:1])
+        prompt = await apply_chat_template(base_url, messages)
+        # Where the rendered system turn ends and the request's own turns begin
+        prefix = os.path.commonprefix([system_only, prompt])
+        key = (base_url, hashlib.sha256(prefix.encode()).hexdigest())
+        if key not in lengths:
+            if len(lengths) >= 1024:
+                lengths.clear()
+            # Compared as tokens, so a boundary inside a token isn't counted as shared
+            lengths[key] = len(os.path.commonprefix([await tokenize(base_url, system_only, add_special=True),
+                                                     await tokenize(base_url, prompt, add_special=True)]))
+    except (httpx.HTTPError, ValueError, KeyError):
+        return None
+    result = "hit" if cached >= lengths[key] else "miss"
+    SYSTEM_PROMPT_CACHE.labels(model=model, result=result).inc()
+    return result
+
+system_prompt_cache_result.lengths = {}
+
+
+def candidate_logprob(choice: dict) -> float:
+    return sum(token.get("logprob", 0.0) for token in (choice.get("logprobs") or {}).get("content") or [])
+
+
+async def complete_candidates(base_url: str, payload: dict, n: int, best_of: int) -> dict:
+    """Generate best_of completions in parallel and keep the n with the highest summed logprob.
+
+    llama-server returns a single choice per request, so each candidate is its own call.
+    Usage counts every candidate's tokens, since all of them were generated.
+    """
+    if best_of > n:
+        payload = {**payload, "logprobs": True}
+    # A seeded request seeds candidate i with seed + i: distinct choices, still reproducible
+    payloads = [payload if payload.get("seed") is None else {**payload, "seed": (payload["seed"] + i) % 0xFFFFFFFF}
+                for i in range(best_of)]
+    results = await asyncio.gather(*[
+        with_upstream_retries(lambda p=p: upstream_completion(base_url, p)) for p in payloads
+    ])
+    choices = [result["choices"][0] for result in results]
+    # Each choice keeps its own finish_reason and token count from its generation
+    for choice, result in zip(choices, results):
+        choice["completion_tokens"] = (result.get("usage") or {}).get("completion_tokens")
+    if best_of > n:
+        choices.sort(key=candidate_logprob, reverse=True)
+    data = results[0]
+    data["choices"] = choices[:n]
+    for index, choice in enumerate(data["choices"]):
+        choice["index"] = index
+        choice.pop("logprobs", None)
+    usages = [result.get("usage") or {} for result in results]
+    if all(usages):
+        completion_tokens = sum(usage.get("completion_tokens", 0) for usage in usages)
+        data["usage"] = {"prompt_tokens": usages[0].get("prompt_tokens", 0),
+                         "completion_tokens": completion_tokens,
+                         "total_tokens": usages[0].get("prompt_tokens", 0) + completion_tokens}
+    return data
+
+
+WORD_PATTERN = re.compile(r"\S+")
+
+
+class RepetitionGuard:
+    """Spots the first n-gram of words occurring more than `limit` times in a growing text."""
+
+    def __init__(self, size: int, limit: int):
+        self.size, self.limit = size, limit
+        self.counts = {}
+        # Complete words already counted
+        self.checked = 0
+
+    def check(self, text: str) -> Optional[int]:
+        """Where to cut the text (the start of the excess occurrence), or None."""
+        words = [match.span() for match in WORD_PATTERN.finditer(text)]
+        if words and words[-1][1] == len(text):
+            # The last word may still be growing
+            words.pop()
+        for i in range(max(self.checked, self.size - 1), len(words)):
+            gram = tuple(text[start:end] for start, end in words[i - self.size + 1:i + 1])
+            self.counts[gram] = self.counts.get(gram, 0) + 1
+            if self.counts[gram] > self.limit:
+                return words[i - self.size + 1][0]
+        self.checked = len(words)
+        return None
+
+
+def repetition_guard(request: ChatRequest) -> Optional[RepetitionGuard]:
+    limit = request.ngram_repeat_limit if request.ngram_repeat_limit is not None \
+        else settings.ngram_repeat_limit
+    if limit <= 0:
+        return None
+    return RepetitionGuard(request.ngram_size or settings.ngram_size, limit)
+
+
+async def collect_stream(base_url: str, payload: dict, stop_pattern: Optional["re.Pattern"] = None,
+                         stops: Optional[List[List[Optional[str]]]] = None, fold: bool = False,
+                         max_bytes: int = 0, guard: Optional[RepetitionGuard] = None):
+    """Run a completion in streaming mode and assemble a chat.completion from the chunks.
+
+    Returns the response and the arrival time of each content chunk, which lets callers
+    observe generation as it happens (timings, early stopping). With `stop_pattern`,
+    the output ends at the first match's end, and with `stops` just before the first
+    stop string; past `max_bytes` it is cut with finish_reason length, and where `guard`
+    finds a looping n-gram with finish_reason repetition. Closing the stream stops llama.cpp.
+    Tool calls are assembled from their deltas like the streaming endpoint does; builds
+    that can't stream tools get a plain completion instead.
+    """
+    content, tool_calls, token_times, scanned, output_bytes = [], [], [], 0, 0
+    data = {"choices": [{"index": 0, "message": {"role": "assistant", "content": ""},
+                         "finish_reason": None}]}
+    async with http_client.stream("POST", f"{base_url}/v1/chat/completions",
+                                  json={**payload, "stream": True}, timeout=120.0) as response:
+        if response.status_code >= 400:
+            body = (await response.aread()).decode("utf-8", errors="replace")
+            if payload.get("tools") and TOOLS_STREAM_UNSUPPORTED_PATTERN.search(body):
+                return await upstream_completion(base_url, payload), []
+            response.raise_for_status()
+        async for line in upstream_lines(response):
+            event = parse_sse_line(line)
+            if event is None or event == "[DONE]":
+                continue
+            for key in ("id", "created", "model", "usage", "timings"):
+                if event.get(key) is not None:
+                    data[key] = event[key]
+            for choice in event.get("choices", []):
+                merge_tool_call_deltas(tool_calls, (choice.get("delta") or {}).get("tool_calls") or [])
+                piece = (choice.get("delta") or {}).get("content")
+                if piece:
+                    content.append(piece)
+                    token_times.append(time.time())
+                    output_bytes += len(piece.encode())
+                if choice.get("finish_reason"):
+                    data["choices"][0]["finish_reason"] = choice["finish_reason"]
+            end = stop_offset("".join(content), stop_pattern) if stop_pattern and content else None
+            if stops and content:
+                end, scanned = find_stop("".join(content), stops, fold, scanned)
+            if end is not None:
+                content = ["".join(content)[:end]]
+                data["choices"][0]["finish_reason"] = "stop"
+            elif guard and content:
+                end = guard.check("".join(content))
+                if end is not None:
+                    content = ["".join(content)[:end]]
+                    data["choices"][0]["finish_reason"] = "repetition"
+            if max_bytes and output_bytes > max_bytes and len("".join(content).encode()) > max_bytes:
+                content = [truncate_utf8("".join(content), max_bytes)]
+                data["choices"][0]["finish_reason"] = "length"
+                end = len(content[0])
+            if end is not None:
+                # Usage arrives with the last chunk, which an early stop never reads
+                data.pop("usage", None)
+                break
+    data["choices"][0]["message"]["content"] = "".join(content)
+    if tool_calls:
+        data["choices"][0]["message"]["tool_calls"] = tool_calls
+        data["choices"][0]["message"]["content"] = "".join(content) or None
+    return data, token_times
+
+
+# Sent with every stream so proxies (nginx honours X-Accel-Buffering) pass chunks on
+# unbuffered; uvicorn's asyncio/uvloop transports already set TCP_NODELAY
+STREAM_HEADERS = {"Cache-Control": "no-cache", "X-Accel-Buffering": "no"}
+
+
+# Streams started with ?cancel_token= stop when POST /v1/cancel/{token} drops a marker
+# file here; files (not memory) so a cancel reaching any uvicorn worker works
+CANCEL_DIR = "/tmp/mindforge-cancel"
+CANCEL_TOKEN_PATTERN = re.compile(r"^[A-Za-z0-9_\-]{1,128}$")
+CANCEL_POLL_SECONDS = 0.25
+
+
+def cancel_marker(token: str) -> str:
+    return os.path.join(CANCEL_DIR, token)
+
+
+async def with_keepalives(lines, interval: float, cancelled: Optional[Callable[[], bool]] = None):
+    """Yield upstream lines, or None each time `interval` seconds pass without one.
+
+    Stops early once `cancelled()` returns true, which is polled a few times a second.
+    """
+    iterator = lines.__aiter__()
+    pending = asyncio.ensure_future(iterator.__anext__())
+    last_line = time.monotonic()
+    try:
+        while True:
+            timeouts = [interval - (time.monotonic() - last_line)] if interval > 0 else []
+            if cancelled:
+                timeouts.append(CANCEL_POLL_SECONDS)
+            # wait() (unlike wait_for) leaves the read running when it times out
+            done, _ = await asyncio.wait({pending}, timeout=max(0, min(timeouts)) if timeouts else None)
+            if cancelled and cancelled():
+                return
+            if not done:
+                if interval > 0 and time.monotonic() - last_line >= interval:
+                    last_line = time.monotonic()
+                    yield None
+                continue
+            try:
+                line = pending.result()
+            except StopAsyncIteration:
+                return
+            last_line = time.monotonic()
+            yield line
+            pending = asyncio.ensure_future(iterator.__anext__())
+    finally:
+        pending.cancel()
+
+
+def build_timings(started: float, token_times: List[float], upstream: Optional[dict]) -> dict:
+    """Summarize prompt vs generation latency for ?profile=true responses."""
+    finished = time.time()
+    timings = {
+        "first_token_ms": round((token_times[0] - started) * 1000, 2) if token_times else None,
+        "token_intervals_ms": [round((b - a) * 1000, 2) for a, b in zip(token_times, token_times[1:])],
+        "total_ms": round((finished - started) * 1000, 2),
+    }
+    if upstream:
+        # llama.cpp's own measurements exclude HTTP and proxy overhead
+        timings["prompt_eval_ms"] = upstream.get("prompt_ms")
+        timings["generation_ms"] = upstream.get("predicted_ms")
+    return timings
+
+
+def stream_stats(started: float, first_token_at: Optional[float], completion_tokens: Optional[int]) -> dict:
+    """The payload of the `event: stats` sent before [DONE] on ?stats=true streams."""
+    finished = time.time()
+    generating = finished - first_token_at if first_token_at else 0
+    return {
+        "ttft_ms": round((first_token_at - started) * 1000, 2) if first_token_at else None,
+        "total_ms": round((finished - started) * 1000, 2),
+        "completion_tokens": completion_tokens,
+        "tokens_per_second": round(completion_tokens / generating, 2)
+        if completion_tokens and generating > 0 else None,
+    }
+
+
+# conversation_id -> (last used, messages); least recently used first
+conversations: "OrderedDict[str, tuple]" = OrderedDict()
+
+
+def evict_conversations():
+    cutoff = time.time() - settings.conversation_ttl
+    while conversations and next(iter(conversations.values()))[0] < cutoff:
+        conversations.popitem(last=False)
+    while len(conversations) > settings.conversation_max:
+        conversations.popitem(last=False)
+
+
+def conversation_history(request: ChatRequest) -> List[dict]:
+    """Prior turns stored for the request's conversation_id followed by its new messages."""
+    new_messages = [m.model_dump(exclude_none=True) for m in request.messages]
+    if not request.conversation_id:
+        return new_messages
+    evict_conversations()
+    stored = conversations.get(request.conversation_id)
+    return (list(stored[1]) if stored else []) + new_messages
+
+
+def save_conversation(request: ChatRequest, reply: Optional[dict]):
+    if not request.conversation_id or not reply:
+        return
+    history = conversation_history(request) + [reply]
+    conversations[request.conversation_id] = (time.time(), history)
+    conversations.move_to_end(request.conversation_id)
+    evict_conversations()
+
+
+def build_messages(request: ChatRequest, prompt: Optional[str]) -> List[dict]:
+    """Convert request messages for llama-server, prepending the stored prompt if any."""
+    messages = conversation_history(request)
+    if prompt:
+        messages.insert(0, {"role": "system", "content": read_prompt_file(prompt)})
+    merge = request.merge_consecutive_roles
+    if merge if merge is not None else settings.merge_consecutive_roles:
+        messages = merge_consecutive_roles(messages, settings.merge_roles_separator)
+    return messages
+
+
+def merge_consecutive_roles(messages: List[dict], separator: str) -> List[dict]:
+    """Concatenate adjacent plain-text messages that share a role.
+
+    Some templates/models expect strict role alternation. Tool calls and tool results
+    are left alone since each one is addressed individually.
+    """
+    merged = []
+    for message in messages:
+        previous = merged[-1] if merged else None
+        if (previous is not None and previous["role"] == message["role"]
+                and set(previous) <= {"role", "content"} and set(message) <= {"role", "content"}):
+            previous["content"] = separator.join(
+                part for part in (previous.get("content"), message.get("content")) if part)
+            continue
+        merged.append(dict(message))
+    return merged
+
+
+# Samplers that only matter when sampling. At temperature 0 the most likely token
+# always survives top_p/top_k-style truncation, and mirostat has nothing to tune,
+# so greedy requests drop them and identical prompts reach llama.cpp identically.
+GREEDY_IGNORED_SAMPLERS = ("top_p", "top_k", "typical_p", "tfs_z", "mirostat", "mirostat_tau", "mirostat_eta")
+
+
+def apply_greedy_precedence(payload: dict) -> dict:
+    """Temperature 0 means greedy decoding whatever the other samplers say, as in OpenAI's API."""
+    if payload.get("temperature") == 0:
+        for field in GREEDY_IGNORED_SAMPLERS:
+            payload.pop(field, None)
+    return payload
+
+
+def resolve_max_tokens(request) -> int:
+    """max_tokens to send: request, then the model's default, then DEFAULT_MAX_TOKENS.
+
+    The result is capped by the model's MODEL_MAX_TOKENS_LIMIT entry, or else by
+    MAX_TOKENS_LIMIT.
+    """
+    model = resolve_model(request.model)
+    value = request.max_tokens
+    if value is None:
+        value = settings.model_default_max_tokens.get(model, settings.default_max_tokens)
+    limit = settings.model_max_tokens_limit.get(model, settings.max_tokens_limit)
+    return min(value, limit) if limit > 0 else value
+
+
+# Built-in sampler defaults; None leaves the field to llama.cpp
+SAMPLER_DEFAULTS = {"temperature": 0.7, "top_p": None, "top_k": None, "repeat_penalty": None}
+
+
+def sampler_value(request: ChatRequest, field: str):
+    """Resolve a sampler field: request value, then env default, then built-in default."""
+    for value in (getattr(request, field), settings.default_sampler[field]):
+        if value is not None:
+            return value
+    return SAMPLER_DEFAULTS[field]
+
+
+def build_payload(request: ChatRequest, messages: List[dict], stream: bool = False) -> dict:
+    temperature = sampler_value(request, "temperature")
+    if temperature is not None and temperature < settings.temperature_floor:
+        temperature = 0.0
+    payload = {
+        "model": request.model,
+        "messages": messages,
+        "temperature": temperature,
+        "max_tokens": resolve_max_tokens(request),
+    }
+    for field in ("top_p", "top_k", "repeat_penalty"):
+        value = sampler_value(request, field)
+        if value is not None:
+            payload[field] = value
+    if request.seed is not None:
+        payload["seed"] = request.seed
+    # Tool calling is handled by llama-server's jinja chat templates (--jinja)
+    if request.tools:
+        payload["tools"] = request.tools
+        if request.tool_choice is not None:
+            payload["tool_choice"] = request.tool_choice
+    # Mirostat replaces top_k/top_p sampling in llama.cpp when enabled
+    for field in ("mirostat", "mirostat_tau", "mirostat_eta", "typical_p", "tfs_z"):
+        if getattr(request, field) is not None:
+            payload[field] = getattr(request, field)
+    # Reusing a cached prompt can change the output slightly, which would break the
+    # seed reproducibility contract, so seeded requests always evaluate afresh (recent
+    # llama.cpp builds cache by default, hence the explicit false)
+    if request.seed is not None:
+        payload["cache_prompt"] = False
+    elif settings.system_prompt_cache:
+        payload["cache_prompt"] = True
+    if stream:
+        payload["stream"] = True
+        if request.stream_options:
+            payload["stream_options"] = request.stream_options
+    return apply_greedy_precedence(payload)
 
 
 @app.get("/")
 async def root():
     return {"status": "ok"}
 
+async def model_capabilities(name: str) -> dict:
+    """What a served model can do, so clients can pick endpoints without trial and error.
+
+    Embedding models run llama-server in embeddings-only mode and serve nothing
+    else. Vision and the loaded context come from /props; both are unknown (false
+    and null) while the model's server is unreachable.
+    """
+    embedding = name in settings.embedding_models
+    try:
+        props = await upstream_props(upstream_url(name))
+    except (httpx.HTTPError, ValueError):
+        props = {}
+    return {
+        "supports_embeddings": embedding,
+        "supports_vision": not embedding and bool((props.get("modalities") or {}).get("vision")),
+        "supports_tools": not embedding and settings.jinja,
+        # Requests have no grammar or json_schema field to forward to llama.cpp
+        "supports_grammar": False,
+        "context_length": props.get("default_generation_settings", {}).get("n_ctx"),
+    }
+
+
+@app.get("/v1/models")
+async def list_models():
+    # Without MODELS a single model answers to any name; entrypoint.sh calls it "default"
+    names = list(settings.models) or ["default"]
+    capabilities = dict(zip(names, await asyncio.gather(*[model_capabilities(name) for name in names])))
+    data = [{"id": name, "object": "model", "owned_by": "mindforge", **capabilities[name]} for name in names]
+    if settings.advertise_aliases:
+        data += [{"id": alias, "object": "model", "owned_by": "mindforge", "alias_for": model,
+                  **capabilities.get(model, {})}
+                 for alias, model in settings.model_aliases.items()]
+    return {"object": "list", "data": data}
+
+@app.get("/prompts")
+async def prompts():
+    details = []
+    for name in list_prompts():
+        try:
+            details.append(prompt_metadata(name))
+        except HTTPException as e:
+            # One broken template should not hide the rest of the catalog
+            details.append({"name": name, "error": e.detail})
+    return {"prompts": [entry["name"] for entry in details], "details": details}
+
+# Demo chat UI, read once at startup; scripts and styles are separate files so a
+# strict Content-Security-Policy (no inline code) still works
+STATIC_DIR = os.path.join(os.path.dirname(os.path.abspath(__file__)), "static")
+UI_FILES = {}
+if env_bool("ENABLE_UI"):
+    for name in ("ui.html", "ui.js", "ui.css"):
+        with open(os.path.join(STATIC_DIR, name), 'r', encoding='utf-8') as f:
+            UI_FILES[name] = f.read()
+
+def ui_file(name: str, media_type: str) -> Response:
+    if name not in UI_FILES:
+        raise HTTPException(status_code=404, detail="Not Found")
+    return Response(content=UI_FILES[name], media_type=media_type)
+
+@app.get("/ui")
+async def ui():
+    return ui_file("ui.html", "text/html")
+
+@app.get("/ui/app.js")
+async def ui_script():
+    return ui_file("ui.js", "application/javascript")
+
+@app.get("/ui/app.css")
+async def ui_styles():
+    return ui_file("ui.css", "text/css")
+
 @app.get("/healthz")
 async def healthz():
-    # Cache health check results for 5 seconds
+    if draining:
+        return JSONResponse(status_code=503, content={"status": "draining", "reason": "draining"})
+    if os.path.exists(NOT_READY_FILE):
+        return JSONResponse(status_code=503, content={"status": "not_ready", "reason": "aborted"})
+    return await upstream_health()
+
+
+async def upstream_health() -> dict:
+    """Upstream and per-model state, checked at most every 5 seconds."""
     current_time = time.time()
-    last_check = getattr(healthz, "last_check_time", 0)
-    last_status = getattr(healthz, "last_status", None)
+    last_check = getattr(upstream_health, "last_check_time", 0)
+    last_status = getattr(upstream_health, "last_status", None)
 
     # Only check upstream every 5 seconds
     if current_time - last_check > 5 or last_status is None:
+        if not settings.models:
+            try:
+                # Use global client instead of creating a new one
+                r = await http_client.get(f"http://127.0.0.1:{UPSTREAM_BASE_PORT}/", timeout=2.0)
+                if r.status_code < 500:
+                    upstream_health.last_status = {"status": "ok", "upstream": True}
+                else:
+                    upstream_health.last_status = {"status": "degraded", "upstream": False}
+            except Exception:
+                upstream_health.last_status = {"status": "degraded", "upstream": False}
+        else:
+            # llama-server answers /health with 503 while the model is still loading
+            states = {}
+            for name, entry in settings.models.items():
+                try:
+                    r = await http_client.get(f"{entry['url']}/health", timeout=2.0)
+                    states[name] = "ready" if r.status_code == 200 else "loading"
+                except Exception:
+                    states[name] = "unavailable"
+            ready = all(state == "ready" for state in states.values())
+            upstream_health.last_status = {
+                "status": "ok" if ready else "degraded",
+                "upstream": any(state == "ready" for state in states.values()),
+                "models": states,
+            }
+        if settings.model_warmup:
+            upstream_health.last_status["warmup"] = settings.model_warmup
+        upstream_health.last_check_time = current_time
+
+    return upstream_health.last_status
+
+# Written by entrypoint.sh at startup: one {"model", "path", "error"} object per failed load
+MODEL_ERRORS_FILE = "/tmp/mindforge-model-errors.jsonl"
+
+
+def model_load_errors() -> List[dict]:
+    try:
+        with open(MODEL_ERRORS_FILE, 'r', encoding='utf-8') as f:
+            errors = [json.loads(line) for line in f if line.strip()]
+    except (OSError, ValueError):
+        return []
+    if settings.log_redact_pattern is not None:
+        for error in errors:
+            error["error"] = settings.log_redact_pattern.sub("[REDACTED]", error["error"])
+    return errors
+
+
+@app.get("/admin/model-status")
+async def model_status(request: Request):
+    """Per-model health plus why any model failed to load, for diagnosis without log access.
+
+    Load errors carry model paths and llama-server log lines, so this needs X-Admin-Key.
+    """
+    require_admin_key(request)
+    health = await upstream_health()
+    states = health.get("models") or {"default": "ready" if health.get("upstream") else "unavailable"}
+    # A model that is up now has nothing left to report
+    errors = [error for error in model_load_errors() if states.get(error["model"]) != "ready"]
+    return {"status": health["status"], "models": states, "load_errors": errors}
+
+
+# Written by POST /admin/abort-all?not_ready=true so every worker's /healthz fails;
+# entrypoint.sh removes it at container start
+NOT_READY_FILE = "/tmp/mindforge-not-ready"
+
+
+def sibling_workers() -> List[int]:
+    """PIDs of the other uvicorn workers; none when this process is the only one."""
+    if int(os.getenv("UVICORN_WORKERS", "1")) <= 1:
+        return []
+    pids = []
+    for children in glob.glob(f"/proc/{os.getppid()}/task/*/children"):
         try:
-            # Use global client instead of creating a new one
-            r = await http_client.get("http://127.0.0.1:8080/", timeout=2.0)
-            if r.status_code < 500:
-                healthz.last_status = {"status": "ok", "upstream": True}
-            else:
-                healthz.last_status = {"status": "degraded", "upstream": False}
-        except Exception:
-            healthz.last_status = {"status": "degraded", "upstream": False}
-            healthz.last_check_time = current_time
+            with open(children, 'r', encoding='utf-8') as f:
+                pids.extend(int(pid) for pid in f.read().split())
+        except (OSError, ValueError):
+            pass
+    return [pid for pid in pids if pid != os.getpid()]
+
+
+def require_admin_key(request: Request):
+    key = request.headers.get("x-admin-key", "")
+    if not settings.admin_api_key:
+        raise HTTPException(status_code=403, detail="Set ADMIN_API_KEY to enable admin actions")
+    if not hmac.compare_digest(key.encode(), settings.admin_api_key.encode()):
+        raise HTTPException(status_code=401, detail="Missing or invalid X-Admin-Key")
+
+
+@app.post("/admin/abort-all")
+async def abort_all(request: Request, not_ready: bool = False):
+    """Kill switch: abort every in-flight inference, optionally failing readiness too."""
+    require_admin_key(request)
+    if not_ready:
+        with open(NOT_READY_FILE, 'w', encoding='utf-8'):
+            pass
+        logger.warning("Readiness set to not ready by /admin/abort-all")
+    # Other workers abort their own requests when signalled and log how many; their
+    # counts can't be collected here, so the response only counts this worker's
+    workers = sibling_workers()
+    for pid in workers:
+        try:
+            os.kill(pid, signal.SIGUSR2)
+        except OSError:
+            pass
+    return {"aborted_here": abort_all_inferences(), "other_workers_signalled": len(workers),
+            "not_ready": not_ready}
+
+
+@app.post("/admin/ready")
+async def restore_readiness(request: Request):
+    """Undo /admin/abort-all?not_ready=true once the emergency is over."""
+    require_admin_key(request)
+    if os.path.exists(NOT_READY_FILE):
+        os.remove(NOT_READY_FILE)
+        logger.warning("Readiness restored by /admin/ready")
+    return {"not_ready": False}
 
-    return healthz.last_status
 
 @app.post("/v1/chat/completions")
-async def generate(request: ChatRequest, prompt: Optional[str] = None):
-    try:
-        system_prompt = ""
-        if prompt:
-            system_prompt = read_prompt_file(prompt)
-
-        # Prepare messages including system prompt if provided
-        messages = [ {"role": m.role, "content": m.content} for m in request.messages ]
-        if system_prompt:
-            messages.insert(0, {"role":"system", "content": system_prompt})
-
-        payload = {
-            "model": request.model,
-            "messages": messages,
-            "temperature": request.temperature,
-            "max_tokens": request.max_tokens
-        }
+async def generate(request: ChatRequest, response: Response, prompt: Optional[str] = None,
+                   profile: bool = False, output_format: str = Query("json", alias="format"),
+                   debug_prompt: bool = False, include_tokens: bool = False, warnings: bool = False):
+    if output_format not in ("json", "text"):
+        raise HTTPException(status_code=400, detail="format must be 'json' or 'text'")
+    data = await run_chat(request, response, prompt=prompt, profile=profile, debug_prompt=debug_prompt,
+                          include_tokens=include_tokens, include_warnings=warnings)
+    if output_format == "text":
+        # Just the first choice's content, for shell pipelines
+        content = ((data.get("choices") or [{}])[0].get("message") or {}).get("content") or ""
+        return PlainTextResponse(content, headers=dict(response.headers))
+    return data
 
-        headers = {"Content-Type": "application/json"}
 
-        # Use global client instead of creating a new one each time
-        resp = await http_client.post("http://localhost:8080/v1/chat/completions",
-                                    json=payload,
-                                    headers=headers)
-        resp.raise_for_status()
-        return resp.json()
+async def run_chat(request: ChatRequest, response: Response, prompt: Optional[str] = None,
+                   profile: bool = False, debug_prompt: bool = False,
+                   include_tokens: bool = False, include_warnings: bool = False) -> dict:
+    """Run a chat completion through the full pipeline and return the OpenAI-style body."""
+    global in_flight_requests
+    check_memory_pressure()
+    permit = await acquire_permit(request.priority)
+    response.headers.update(permit.headers())
+    in_flight_requests += 1
+    inference = ActiveInference(asyncio.current_task())
+    span = tracer.start_span("chat.request", attributes={"llm.model": request.model})
+    span_context = trace.set_span_in_context(span)
+    started = time.time()
+    inference_attempted = succeeded = False
+    request_log = RequestLog(request.model)
+    try:
+        with tracer.start_as_current_span("chat.format", context=span_context):
+            validate_chat_request(request)
+            check_chat_template(request.model)
+            # Prepare messages including system prompt if provided
+            messages = build_messages(request, prompt)
+            payload = build_payload(request, messages)
+            base_url = upstream_url(request.model)
+            await check_sampler_support(request, base_url)
+            truncation = await truncate_history(request, base_url, payload)
+            response.headers.update(truncation_headers(truncation))
+            await check_prompt_size(base_url, payload)
+            await apply_context_budget(request, base_url, payload)
+            cap = apply_adaptive_max_tokens(payload)
+            if cap is not None:
+                response.headers["X-Max-Tokens-Cap"] = str(cap)
+            warnings = adjustment_warnings(request, payload, truncation)
+            request_log.exchange("prompt", json.dumps(messages, ensure_ascii=False))
+            if debug_prompt:
+                # The exact prompt text sent to the model, after truncation and merging
+                debug = {"chat_template": chat_template_name(request.model),
+                         "prompt": await apply_chat_template(base_url, payload["messages"])}
+                response.headers["X-Chat-Template"] = debug["chat_template"]
+                if include_tokens:
+                    debug["prompt_token_ids"] = await tokenize(base_url, debug["prompt"])
+
+        n, best_of = request.n or 1, request.best_of or request.n or 1
+
+        # From here on a failure may come after llama.cpp processed the prompt
+        inference_attempted = True
+        with tracer.start_as_current_span("chat.inference", context=span_context):
+            if best_of > 1:
+                if profile:
+                    raise HTTPException(status_code=400,
+                                        detail="profile is not supported with n or best_of")
+                if request.stop:
+                    # Candidates aren't streamed, so llama.cpp stops on exact matches and
+                    # whitespace or case variants are trimmed afterwards
+                    payload = {**payload, "stop": [request.stop] if isinstance(request.stop, str)
+                               else request.stop}
+                data = await complete_candidates(base_url, payload, n, best_of)
+            elif profile or request.stop_regex or request.stop or settings.max_output_bytes \
+                    or repetition_guard(request):
+                # Stream internally so each token's arrival time can be recorded, and stop
+                # strings, stop_regex, MAX_OUTPUT_BYTES or the n-gram guard can end generation
+                # right away
+                stop_pattern = re.compile(request.stop_regex) if request.stop_regex else None
+                inference_started = time.time()
+                data, token_times = await with_upstream_retries(
+                    lambda: collect_stream(base_url, payload, stop_pattern, request_stops(request),
+                                           bool(request.stop_case_insensitive), settings.max_output_bytes,
+                                           repetition_guard(request)))
+                if data.get("usage") is None:
+                    data["usage"] = await stream_usage(base_url, payload["messages"],
+                                                       data["choices"][0]["message"]["content"] or "")
+                if profile:
+                    data["timings"] = build_timings(inference_started, token_times, data.get("timings"))
+            else:
+                data = await with_upstream_retries(lambda: upstream_completion(base_url, payload))
+            add_cached_tokens(data.get("usage"), data.get("timings"))
+            cache_result = await system_prompt_cache_result(request.model, base_url, payload,
+                                                            data.get("usage"), data.get("timings"))
+            if cache_result:
+                response.headers["X-System-Prompt-Cache"] = cache_result
 
+        with tracer.start_as_current_span("chat.response", context=span_context):
+            normalize_response(data, "chat.completion")
+            choices = data.get("choices", [])
+            if len(choices) == 1 and "completion_tokens" not in choices[0]:
+                choices[0]["completion_tokens"] = (data.get("usage") or {}).get("completion_tokens")
+            stops = request_stops(request)
+            # Only candidates can still contain a stop; streamed generations end before one
+            for choice in choices if stops else []:
+                message = choice.get("message") or {}
+                end, _ = find_stop(message.get("content") or "", stops, bool(request.stop_case_insensitive))
+                if end is not None:
+                    message["content"] = message["content"][:end]
+                    choice["finish_reason"] = "stop"
+            # Likewise candidates can exceed MAX_OUTPUT_BYTES
+            for choice in choices if settings.max_output_bytes else []:
+                message = choice.get("message") or {}
+                if len((message.get("content") or "").encode()) > settings.max_output_bytes:
+                    message["content"] = truncate_utf8(message["content"], settings.max_output_bytes)
+                    choice["finish_reason"] = "length"
+            post_process(data, request)
+            data["system_fingerprint"] = await system_fingerprint(base_url)
+            if request.seed is not None:
+                data["seed"] = request.seed
+            add_cost(data.get("usage"))
+            if truncation:
+                data["truncated"] = True
+                data["truncation"] = truncation
+            if debug_prompt:
+                data["debug"] = {**debug, **await context_stats(request, base_url, data)}
+            if include_warnings:
+                data["warnings"] = warnings
+            for choice in data.get("choices", []):
+                request_log.exchange("completion", (choice.get("message") or {}).get("content") or "")
+            if request.conversation_id:
+                save_conversation(request, (data.get("choices") or [{}])[0].get("message"))
+                data["conversation_id"] = request.conversation_id
+        usage = data.get("usage") or {}
+        span.set_attribute("llm.prompt_tokens", usage.get("prompt_tokens", 0))
+        span.set_attribute("llm.completion_tokens", usage.get("completion_tokens", 0))
+        request_log.done(usage, int((time.time() - started) * 1000))
+        succeeded = True
+        return data
+
+    except asyncio.CancelledError:
+        if not inference.aborted:
+            raise
+        span.set_attribute("http.status_code", 503)
+        raise inference.aborted_error()
+    except HTTPException as e:
+        span.set_attribute("http.status_code", e.status_code)
+        raise
     except httpx.RequestError as e:
-        raise HTTPException(status_code=502, detail=f"Upstream request error: {str(e)}")
+        # A refused connection never reached llama.cpp; other errors (timeouts, dropped
+        # connections) may have happened mid-generation
+        usage = await partial_usage(base_url, payload["messages"]) \
+            if inference_attempted and not isinstance(e, httpx.ConnectError) else None
+        raise UpstreamFailure(502, f"Upstream request error: {str(e)}", usage)
     except httpx.HTTPStatusError as e:
-        raise HTTPException(status_code=500, detail=f"Upstream server error: {str(e)}")
+        unavailable = upstream_unavailable_error(e.response.status_code, e.response.text)
+        if unavailable:
+            raise unavailable
+        usage = await partial_usage(base_url, payload["messages"]) if inference_attempted else None
+        raise UpstreamFailure(500, f"Upstream server error: {str(e)}", usage)
     except Exception as e:
         raise HTTPException(status_code=500, detail=str(e))
+    finally:
+        if not succeeded:
+            # Failures are always logged, sampled or not
+            failure = sys.exc_info()[1]
+            request_log.failed(getattr(failure, "status_code", None),
+                               getattr(failure, "detail", None) or repr(failure),
+                               int((time.time() - started) * 1000))
+        in_flight_requests -= 1
+        inference.done()
+        permit.release()
+        span.set_attribute("llm.latency_ms", int((time.time() - started) * 1000))
+        span.end()
 
 @app.post("/v1/chat/completions/stream")
-async def generate_stream(request: ChatRequest, prompt: Optional[str] = None):
+async def generate_stream(request: ChatRequest, prompt: Optional[str] = None, debug_prompt: bool = False,
+                          cancel_token: Optional[str] = None, warnings: bool = False,
+                          stats: bool = False):
+    check_memory_pressure()
+    # Validate before streaming starts so errors still get a proper status code
+    validate_chat_request(request, stream=True)
+    check_chat_template(request.model)
+    if cancel_token is not None:
+        if not CANCEL_TOKEN_PATTERN.match(cancel_token):
+            raise HTTPException(status_code=400,
+                                detail="cancel_token must be 1-128 letters, digits, '_' or '-'")
+        # A marker left from an earlier stream with this token must not cancel this one
+        if os.path.exists(cancel_marker(cancel_token)):
+            os.remove(cancel_marker(cancel_token))
+    base_url = upstream_url(request.model)
+    messages = build_messages(request, prompt)
+    payload = build_payload(request, messages, stream=True)
+    try:
+        await check_sampler_support(request, base_url)
+        truncation = await truncate_history(request, base_url, payload)
+        await check_prompt_size(base_url, payload)
+        await apply_context_budget(request, base_url, payload)
+    except httpx.HTTPError as e:
+        raise HTTPException(status_code=502, detail=f"Upstream request error: {str(e)}")
+    fingerprint = await system_fingerprint(base_url)
+    response_headers = truncation_headers(truncation)
+    if debug_prompt:
+        # Streams have no body field to carry the prompt; only the template is reported
+        response_headers["X-Chat-Template"] = chat_template_name(request.model)
+    cap = apply_adaptive_max_tokens(payload, pending=1)
+    if cap is not None:
+        response_headers["X-Max-Tokens-Cap"] = str(cap)
+    # Streams have no body to carry them, so the adjustments go in a header
+    adjustments = adjustment_warnings(request, payload, truncation) if warnings else []
+    if adjustments:
+        response_headers["X-Warnings"] = "; ".join(adjustments)
+    permit = await acquire_permit(request.priority, stream=True)
+    response_headers.update(permit.headers())
+
     async def response_generator():
+        global in_flight_requests
+        in_flight_requests += 1
+        inference = ActiveInference()
+        span = tracer.start_span("chat.stream", attributes={"llm.model": request.model})
+        started = time.time()
+        request_log = RequestLog(request.model, stream=True)
+        # Generated text so far, also for the partial usage reported if the stream fails
+        completion, tool_calls = [], []
         try:
-            system_prompt = ""
-            if prompt:
-                system_prompt = read_prompt_file(prompt)
-
-            messages = [{"role": m.role, "content": m.content} for m in request.messages]
-            if system_prompt:
-                messages.insert(0, {"role": "system", "content": system_prompt})
-
-            payload = {
-                "model": request.model,
-                "messages": messages,
-                "temperature": request.temperature,
-                "max_tokens": request.max_tokens,
-                "stream": True
-            }
+            request_log.exchange("prompt", json.dumps(messages, ensure_ascii=False))
 
             headers = {"Content-Type": "application/json"}
 
             async with http_client.stream(
                 "POST",
-                "http://localhost:8080/v1/chat/completions",
+                f"{base_url}/v1/chat/completions",
                 json=payload,
                 headers=headers,
                 timeout=120.0
             ) as response:
-                async for chunk in response.aiter_bytes():
-                    yield chunk
+                if response.status_code >= 400:
+                    body = (await response.aread()).decode("utf-8", errors="replace")
+                    err = upstream_unavailable_error(response.status_code, body)
+                    if err:
+                        request_log.failed(err.status_code, err.detail, int((time.time() - started) * 1000))
+                        yield json.dumps({"error": err.detail,
+                                          "retry_after": int(err.headers["Retry-After"])}).encode()
+                        return
+                    if request.tools and TOOLS_STREAM_UNSUPPORTED_PATTERN.search(body):
+                        # Generate in one go and replay the result as tool-call deltas
+                        data = await upstream_completion(base_url, {
+                            k: v for k, v in payload.items() if k not in ("stream", "stream_options")})
+                        normalize_response(data, "chat.completion")
+                        post_process(data, request)
+                        data["system_fingerprint"] = fingerprint
+                        for event in replayed_stream_events(data):
+                            if request.seed is not None:
+                                event["seed"] = request.seed
+                            yield sse_event(event)
+                        if (request.stream_options or {}).get("include_usage") and data.get("usage"):
+                            add_cached_tokens(data["usage"], data.get("timings"))
+                            yield sse_event(usage_event(event, data["usage"]))
+                        if stats:
+                            # Generated in one go, so the first token arrived with the rest
+                            yield sse_event(stream_stats(started, time.time(),
+                                                         (data.get("usage") or {}).get("completion_tokens")),
+                                            "stats")
+                        yield sse_event("[DONE]")
+                        save_conversation(request, (data.get("choices") or [{}])[0].get("message"))
+                        request_log.done(data.get("usage"), int((time.time() - started) * 1000))
+                        return
+                    error = {"error": f"Upstream server error: {body}"}
+                    request_log.failed(response.status_code, error["error"], int((time.time() - started) * 1000))
+                    usage = await partial_usage(base_url, payload["messages"])
+                    if usage is not None:
+                        error["usage"] = usage
+                    yield json.dumps(error).encode()
+                    return
+                # Batch several token chunks per SSE event to cut per-event overhead
+                chunk_tokens = request.stream_chunk_tokens or settings.stream_chunk_tokens
+                # Streams apply the special-token and content-filter stages incrementally
+                strip_special = stage_active("strip_special_tokens", request)
+                batch = []
+                # With include_usage, usage moves to a final choices-less chunk before [DONE]
+                include_usage = bool((request.stream_options or {}).get("include_usage"))
+                usage, last_event, timings = None, None, None
+                # For ?stats=true: arrival of the first content token, and content events seen
+                first_token_at, token_events = None, 0
+
+                def stats_event() -> bytes:
+                    # llama.cpp's count when it reported one, else usage, else one token per event
+                    tokens = (timings or {}).get("predicted_n") or (usage or {}).get("completion_tokens") \
+                        or token_events
+                    return sse_event(stream_stats(started, first_token_at, tokens), "stats")
+                # Text already sent can't be recalled, so a filter hit drops the pending
+                # batch and ends the stream with finish_reason content_filter
+                filtering = stage_active("content_filter", request)
+                stop_pattern = re.compile(request.stop_regex) if request.stop_regex else None
+                # Stop strings: text that may be the start of one is held back, and `sent`
+                # counts the characters of `completion` already passed on
+                stops, sent = request_stops(request), 0
+                fold = bool(request.stop_case_insensitive)
+                guard = repetition_guard(request)
+
+                output_cap = OutputCap(settings.max_output_bytes)
+
+                def cap_content(content: str) -> str:
+                    """Trim text about to be passed on to MAX_OUTPUT_BYTES, ending the stream if hit."""
+                    nonlocal early_finish, completion
+                    capped = output_cap.take(content)
+                    if len(capped) < len(content):
+                        completion = ["".join(completion)[:output_cap.chars]]
+                        early_finish = "length"
+                    return capped
+
+                def release_held(template: Optional[dict]):
+                    """Queue held-back text once it can no longer turn into a stop."""
+                    nonlocal sent
+                    held = "".join(completion)[sent:]
+                    if stops and held and template is not None:
+                        sent += len(held)
+                        held = cap_content(held)
+                        if held:
+                            batch.append({**template, "choices": [{"index": 0, "delta": {"content": held},
+                                                                   "finish_reason": None}]})
+
+                early_finish, finished = None, False
+                def cancelled() -> bool:
+                    return inference.aborted or bool(cancel_token) and os.path.exists(cancel_marker(cancel_token))
+
+                async for line in with_keepalives(upstream_lines(response), settings.stream_keepalive,
+                                                  cancelled):
+                    if line is None:
+                        # SSE comment: keeps proxies and clients from timing out before the first token
+                        yield b": keepalive\n\n"
+                        continue
+                    event = parse_sse_line(line)
+                    if event is None:
+                        continue
+                    if event == "[DONE]":
+                        release_held(last_event)
+                        if batch:
+                            yield sse_event(merge_content_events(batch))
+                            batch = []
+                        if include_usage and last_event is not None:
+                            usage = usage or await stream_usage(base_url, messages, "".join(completion))
+                            add_cached_tokens(usage, timings)
+                            yield sse_event(usage_event(last_event, usage))
+                            include_usage = False
+                        if stats:
+                            yield stats_event()
+                        yield sse_event(event)
+                        finished = True
+                        continue
+                    normalize_response(event, "chat.completion.chunk")
+                    event["system_fingerprint"] = fingerprint
+                    if request.seed is not None:
+                        event["seed"] = request.seed
+                    last_event = event
+                    if content_delta(event) is not None:
+                        token_events += 1
+                        first_token_at = first_token_at or time.time()
+                    if "timings" in event:
+                        timings = event["timings"]
+                    if include_usage and "usage" in event:
+                        usage = event.pop("usage") or usage
+                        if not event.get("choices"):
+                            continue
+                    if strip_special:
+                        strip_special_tokens(event.get("choices", []), "delta")
+                    for choice in event.get("choices", []):
+                        completion.append((choice.get("delta") or {}).get("content") or "")
+                        merge_tool_call_deltas(tool_calls, (choice.get("delta") or {}).get("tool_calls") or [])
+                    piece = content_delta(event)
+                    if stops and piece is not None:
+                        text = "".join(completion)
+                        end, hold = find_stop(text, stops, fold, sent)
+                        event["choices"][0]["delta"]["content"] = text[sent:hold]
+                        if end is not None:
+                            completion = [text[:end]]
+                            early_finish = "stop"
+                        elif hold == sent:
+                            continue
+                        sent = hold
+                    elif stops:
+                        # Generation ended (or moved on to tool calls), so held text is no stop
+                        release_held(event)
+                    if stop_pattern and piece is not None:
+                        text = "".join(completion)
+                        end = stop_offset(text, stop_pattern)
+                        if end is not None:
+                            # Keep this chunk's text up to the end of the match
+                            kept = piece[:max(0, end - (len(text) - len(piece)))]
+                            event["choices"][0]["delta"]["content"] = completion[-1] = kept
+                            early_finish = "stop"
+                    if guard and not early_finish and piece is not None:
+                        text = "".join(completion)
+                        cut = guard.check(text)
+                        if cut is not None:
+                            # Text before this event's delta is out (or held as a possible stop);
+                            # what is already out stays, the rest is cut at the repeat
+                            start = (sent if stops else len(text)) - len(content_delta(event) or "")
+                            sent = max(cut, start)
+                            event["choices"][0]["delta"]["content"] = text[start:sent]
+                            completion = [text[:sent]]
+                            early_finish = "repetition"
+                    if content_delta(event) is not None:
+                        event["choices"][0]["delta"]["content"] = cap_content(content_delta(event))
+                    if filtering and output_blocked("".join(completion)):
+                        early_finish = "content_filter"
+                    if early_finish:
+                        break
+                    if chunk_tokens > 1 and content_delta(event) is not None:
+                        batch.append(event)
+                        if len(batch) >= chunk_tokens:
+                            yield sse_event(merge_content_events(batch))
+                            batch = []
+                        continue
+                    # Anything other than plain content (role, tool calls, finish) flushes the batch
+                    if batch:
+                        yield sse_event(merge_content_events(batch))
+                        batch = []
+                    yield sse_event(event)
+                if not early_finish and not finished and cancelled():
+                    if inference.aborted:
+                        logger.info("Stream aborted via /admin/abort-all")
+                    else:
+                        logger.info("Stream cancelled via cancel token %s", cancel_token)
+                    early_finish = "cancelled"
+                if early_finish in (None, "cancelled"):
+                    release_held(last_event)
+                if early_finish and not finished:
+                    if early_finish in ("stop", "length", "repetition") and content_delta(event) is not None:
+                        batch.append(event)
+                    # A filter hit drops the pending batch; anything else is delivered
+                    if batch and early_finish != "content_filter":
+                        yield sse_event(merge_content_events(batch))
+                    yield sse_event(finish_event(last_event or {}, early_finish))
+                    if include_usage:
+                        usage = usage or await stream_usage(base_url, messages, "".join(completion))
+                        add_cached_tokens(usage, timings)
+                        yield sse_event(usage_event(last_event or {}, usage))
+                    if stats:
+                        yield stats_event()
+                    yield sse_event("[DONE]")
+                elif batch:
+                    yield sse_event(merge_content_events(batch))
+                request_log.exchange("completion", "".join(completion))
+                # Keep a blocked reply out of the conversation history
+                blocked = early_finish == "content_filter"
+                reply = {"role": "assistant", "content": "" if blocked else "".join(completion)}
+                if tool_calls and not blocked:
+                    reply["tool_calls"] = tool_calls
+                save_conversation(request, reply)
+                # Headers are long gone, so streams only count towards the metric
+                await system_prompt_cache_result(request.model, base_url, payload, usage, timings)
+                # Token counts are only known when llama-server or include_usage supplied them
+                request_log.done(usage, int((time.time() - started) * 1000))
+
+        except Exception as e:
+            span.record_exception(e)
+            error = {"error": str(e)}
+            request_log.failed(None, repr(e), int((time.time() - started) * 1000))
+            if not isinstance(e, httpx.ConnectError):
+                usage = await partial_usage(base_url, payload["messages"], "".join(completion))
+                if usage is not None:
+                    error["usage"] = usage
+            yield json.dumps(error).encode()
+        finally:
+            in_flight_requests -= 1
+            inference.done()
+            permit.release()
+            if cancel_token and os.path.exists(cancel_marker(cancel_token)):
+                os.remove(cancel_marker(cancel_token))
+            span.set_attribute("llm.latency_ms", int((time.time() - started) * 1000))
+            span.end()
+
+    # The background task covers streams that end before the generator ever runs
+    return StreamingResponse(response_generator(), media_type="application/json",
+                             headers={**STREAM_HEADERS, **response_headers},
+                             background=BackgroundTask(permit.release))
 
+@app.post("/v1/cancel/{token}")
+async def cancel_stream(token: str):
+    if not CANCEL_TOKEN_PATTERN.match(token):
+        raise HTTPException(status_code=400, detail="Invalid cancel token")
+    os.makedirs(CANCEL_DIR, exist_ok=True)
+    # Markers nobody picked up (cancels after their stream ended) expire after an hour
+    now = time.time()
+    for name in os.listdir(CANCEL_DIR):
+        try:
+            if now - os.path.getmtime(cancel_marker(name)) > 3600:
+                os.remove(cancel_marker(name))
+        except OSError:
+            pass
+    with open(cancel_marker(token), 'w', encoding='utf-8'):
+        pass
+    return JSONResponse(status_code=202, content={"cancelled": token})
+
+def completion_payload(request: CompletionRequest) -> dict:
+    temperature = sampler_value(request, "temperature")
+    if temperature is not None and temperature < settings.temperature_floor:
+        temperature = 0.0
+    payload = {"model": request.model, "prompt": request.prompt, "temperature": temperature,
+               "max_tokens": resolve_max_tokens(request)}
+    for field in ("top_p", "top_k", "repeat_penalty"):
+        value = sampler_value(request, field)
+        if value is not None:
+            payload[field] = value
+    if request.seed is not None:
+        payload["seed"] = request.seed
+    if request.stop:
+        payload["stop"] = [request.stop] if isinstance(request.stop, str) else request.stop
+    if request.stream:
+        payload["stream"] = True
+        if request.stream_options:
+            payload["stream_options"] = request.stream_options
+    return apply_greedy_precedence(payload)
+
+
+def validate_completion_request(request: CompletionRequest):
+    problems = []
+    if settings.models and resolve_model(request.model) not in settings.models:
+        problems.append({"field": "model", "message": f"Model {request.model} not found"})
+    if request.temperature is not None and request.temperature < 0:
+        problems.append({"field": "temperature", "message": "must not be negative"})
+    if request.max_tokens is not None and request.max_tokens < 1:
+        problems.append({"field": "max_tokens", "message": "must be at least 1"})
+    if request.seed is not None and not 0 <= request.seed < 0xFFFFFFFF:
+        problems.append({"field": "seed", "message": f"must be between 0 and {0xFFFFFFFF - 1}"})
+    stops = [request.stop] if isinstance(request.stop, str) else request.stop or []
+    if stop_limit_problem(stops):
+        problems.append({"field": "stop", "message": stop_limit_problem(stops)})
+    if request.priority is not None and request.priority not in PRIORITY_RANKS:
+        problems.append({"field": "priority", "message": f"must be one of {', '.join(PRIORITY_RANKS)}"})
+    if problems:
+        raise RequestProblems(problems)
+
+
+def completion_repetition_guard() -> Optional[RepetitionGuard]:
+    # Legacy completions have no per-request override, so NGRAM_REPEAT_LIMIT applies as set
+    if settings.ngram_repeat_limit <= 0:
+        return None
+    return RepetitionGuard(settings.ngram_size, settings.ngram_repeat_limit)
+
+
+def limit_completion_choice(choice: dict, filtering: bool):
+    """Apply the repetition guard, MAX_OUTPUT_BYTES and the content filter to a finished text choice."""
+    text = choice.get("text") or ""
+    guard = completion_repetition_guard()
+    cut = guard.check(text) if guard else None
+    if cut is not None:
+        text, choice["finish_reason"] = text[:cut], "repetition"
+    if settings.max_output_bytes and len(text.encode()) > settings.max_output_bytes:
+        text, choice["finish_reason"] = truncate_utf8(text, settings.max_output_bytes), "length"
+    if filtering and text and output_blocked(text):
+        text, choice["finish_reason"] = filtered_content(text), "content_filter"
+    choice["text"] = text
+
+
+@app.post("/v1/completions")
+async def completions(request: CompletionRequest):
+    """Legacy text completions, proxied to llama-server's /v1/completions.
+
+    The operator's limits apply as they do to chat: MAX_PROMPT_TOKENS, ADAPTIVE_MAX_TOKENS,
+    MAX_OUTPUT_BYTES, NGRAM_REPEAT_LIMIT and the content_filter stage, with the same request
+    logging and partial usage on upstream failures. The other post-processing stages
+    reshape chat replies and are not applied to raw text.
+    """
+    global in_flight_requests
+    check_memory_pressure()
+    validate_completion_request(request)
+    base_url = upstream_url(request.model)
+    payload = completion_payload(request)
+    try:
+        await check_prompt_size(base_url, payload)
+    except httpx.HTTPError as e:
+        raise HTTPException(status_code=502, detail=f"Upstream request error: {str(e)}")
+    fingerprint = await system_fingerprint(base_url)
+    response_headers = {}
+    cap = apply_adaptive_max_tokens(payload, pending=1)
+    if cap is not None:
+        response_headers["X-Max-Tokens-Cap"] = str(cap)
+    filtering = stage_active("content_filter", request)
+    permit = await acquire_permit(request.priority, stream=bool(request.stream))
+    response_headers.update(permit.headers())
+
+    def annotate(data: dict, object_type: str) -> dict:
+        normalize_response(data, object_type)
+        data["system_fingerprint"] = fingerprint
+        add_cached_tokens(data.get("usage"), data.get("timings"))
+        if request.seed is not None:
+            data["seed"] = request.seed
+        add_cost(data.get("usage"))
+        return data
+
+    if not request.stream:
+        in_flight_requests += 1
+        inference = ActiveInference(asyncio.current_task())
+        started = time.time()
+        succeeded = False
+        request_log = RequestLog(request.model)
+        try:
+            request_log.exchange("prompt", request.prompt)
+
+            async def complete() -> dict:
+                resp = await http_client.post(f"{base_url}/v1/completions", json=payload, timeout=120.0)
+                resp.raise_for_status()
+                return upstream_json(resp)
+
+            data = annotate(await with_upstream_retries(complete), "text_completion")
+            for choice in data.get("choices", []):
+                limit_completion_choice(choice, filtering)
+                request_log.exchange("completion", choice.get("text") or "")
+            request_log.done(data.get("usage"), int((time.time() - started) * 1000))
+            succeeded = True
+            return JSONResponse(data, headers=response_headers)
+        except asyncio.CancelledError:
+            if not inference.aborted:
+                raise
+            raise inference.aborted_error()
+        except HTTPException:
+            raise
+        except httpx.RequestError as e:
+            # As for chat: a refused connection never reached llama.cpp
+            usage = None if isinstance(e, httpx.ConnectError) else await partial_usage(base_url, request.prompt)
+            raise UpstreamFailure(502, f"Upstream request error: {str(e)}", usage)
+        except httpx.HTTPStatusError as e:
+            unavailable = upstream_unavailable_error(e.response.status_code, e.response.text)
+            if unavailable:
+                raise unavailable
+            raise UpstreamFailure(500, f"Upstream server error: {str(e)}",
+                                  await partial_usage(base_url, request.prompt))
+        finally:
+            if not succeeded:
+                failure = sys.exc_info()[1]
+                request_log.failed(getattr(failure, "status_code", None),
+                                   getattr(failure, "detail", None) or repr(failure),
+                                   int((time.time() - started) * 1000))
+            in_flight_requests -= 1
+            inference.done()
+            permit.release()
+
+    async def response_generator():
+        global in_flight_requests
+        in_flight_requests += 1
+        inference = ActiveInference()
+        started = time.time()
+        request_log = RequestLog(request.model, stream=True)
+        include_usage = bool((request.stream_options or {}).get("include_usage"))
+        guard = completion_repetition_guard()
+        output_cap = OutputCap(settings.max_output_bytes)
+        # Text passed on so far, also for the partial usage reported if the stream fails
+        completion, early_finish, last_event, usage = [], None, None, None
+        try:
+            request_log.exchange("prompt", request.prompt)
+            async with http_client.stream("POST", f"{base_url}/v1/completions", json=payload,
+                                          timeout=120.0) as response:
+                if response.status_code >= 400:
+                    body = (await response.aread()).decode("utf-8", errors="replace")
+                    err = upstream_unavailable_error(response.status_code, body)
+                    if err:
+                        request_log.failed(err.status_code, err.detail, int((time.time() - started) * 1000))
+                        yield json.dumps({"error": err.detail,
+                                          "retry_after": int(err.headers["Retry-After"])}).encode()
+                        return
+                    error = {"error": f"Upstream server error: {body}"}
+                    request_log.failed(response.status_code, error["error"], int((time.time() - started) * 1000))
+                    usage = await partial_usage(base_url, request.prompt)
+                    if usage is not None:
+                        error["usage"] = usage
+                    yield json.dumps(error).encode()
+                    return
+                async for line in with_keepalives(upstream_lines(response), settings.stream_keepalive,
+                                                  lambda: inference.aborted):
+                    if line is None:
+                        yield b": keepalive\n\n"
+                        continue
+                    event = parse_sse_line(line)
+                    if event is None:
+                        continue
+                    if event == "[DONE]":
+                        yield sse_event(event)
+                        continue
+                    event = annotate(event, "text_completion.chunk")
+                    last_event = event
+                    usage = event.get("usage") or usage
+                    choices = event.get("choices") or []
+                    piece = choices[0].get("text") if len(choices) == 1 else None
+                    if piece:
+                        sent = "".join(completion)
+                        cut = guard.check(sent + piece) if guard else None
+                        if cut is not None:
+                            # What was already passed on stays; the rest is cut at the repeat
+                            piece, early_finish = piece[:max(0, cut - len(sent))], "repetition"
+                        capped = output_cap.take(piece)
+                        if len(capped) < len(piece):
+                            piece, early_finish = capped, "length"
+                        completion.append(piece)
+                        if filtering and output_blocked("".join(completion)):
+                            # Text already sent can't be recalled; this chunk is dropped
+                            early_finish = "content_filter"
+                            break
+                        choices[0]["text"] = piece
+                        if early_finish:
+                            if piece:
+                                choices[0]["finish_reason"] = None
+                                yield sse_event(event)
+                            break
+                    yield sse_event(event)
+                if early_finish:
+                    # Closing the upstream stream stops llama.cpp; the client still gets a proper ending
+                    template = last_event or {}
+                    yield sse_event({**{key: template.get(key) for key in
+                                        ("id", "object", "created", "model", "system_fingerprint")},
+                                     "choices": [{"index": 0, "text": "", "logprobs": None,
+                                                  "finish_reason": early_finish}]})
+                    if include_usage:
+                        usage = usage or await stream_usage(base_url, request.prompt, "".join(completion))
+                        yield sse_event(usage_event(template, usage))
+                    yield sse_event("[DONE]")
+                elif inference.aborted:
+                    yield json.dumps({"error": "Request aborted by the operator"}).encode()
+                request_log.exchange("completion", "".join(completion))
+                request_log.done(usage, int((time.time() - started) * 1000))
         except Exception as e:
-            yield json.dumps({"error": str(e)}).encode()
+            error = {"error": str(e)}
+            request_log.failed(None, repr(e), int((time.time() - started) * 1000))
+            if not isinstance(e, httpx.ConnectError):
+                usage = await partial_usage(base_url, request.prompt, "".join(completion))
+                if usage is not None:
+                    error["usage"] = usage
+            yield json.dumps(error).encode()
+        finally:
+            in_flight_requests -= 1
+            inference.done()
+            permit.release()
+
+    # The background task covers streams that end before the generator ever runs
+    return StreamingResponse(response_generator(), media_type="application/json",
+                             headers={**STREAM_HEADERS, **response_headers},
+                             background=BackgroundTask(permit.release))
+
+@app.post("/v1/embeddings")
+async def embeddings(request: EmbeddingRequest):
+    inputs = [request.input] if isinstance(request.input, str) else request.input
+    if not inputs:
+        raise HTTPException(status_code=400, detail="input must not be empty")
+    if len(inputs) > settings.embeddings_max_inputs:
+        raise HTTPException(status_code=400,
+                            detail=f"input has {len(inputs)} items, maximum is {settings.embeddings_max_inputs}")
+    base_url = upstream_url(request.model)
+    try:
+        # llama-server batches the whole array itself, so send it in one request
+        resp = await http_client.post(f"{base_url}/v1/embeddings",
+                                    json={"model": request.model, "input": inputs,
+                                          "encoding_format": request.encoding_format})
+        resp.raise_for_status()
+        data = resp.json()
+
+        token_counts = await asyncio.gather(*[
+            http_client.post(f"{base_url}/tokenize", json={"content": text}) for text in inputs
+        ])
+        items = sorted(data.get("data", []), key=lambda item: item.get("index", 0))
+        for item, counted in zip(items, token_counts):
+            item["tokens"] = len(counted.json().get("tokens", [])) if counted.status_code == 200 else None
+        data["data"] = items
+        data["object"] = "list"
+        return data
+
+    except httpx.RequestError as e:
+        raise HTTPException(status_code=502, detail=f"Upstream request error: {str(e)}")
+    except httpx.HTTPStatusError as e:
+        raise HTTPException(status_code=500, detail=f"Upstream server error: {str(e)}")
+
+def next_token_candidates(data: dict) -> List[dict]:
+    """Extract the next-token distribution from a llama-server /completion response.
+
+    Recent llama.cpp builds report `top_logprobs` with token ids; older ones only
+    report `probs` with token strings.
+    """
+    first = (data.get("completion_probabilities") or [{}])[0]
+    if "top_logprobs" in first:
+        return [{"token_id": c.get("id"), "token": c.get("token"), "logprob": c["logprob"],
+                 "prob": math.exp(c["logprob"])} for c in first["top_logprobs"]]
+    return [{"token_id": c.get("id"), "token": c.get("tok_str"),
+             "logprob": math.log(c["prob"]) if c["prob"] > 0 else None, "prob": c["prob"]}
+            for c in first.get("probs", [])]
+
+
+@app.post("/v1/logits")
+async def logits(request: LogitsRequest):
+    if (request.prompt is None) == (request.messages is None):
+        raise HTTPException(status_code=400, detail="Provide exactly one of prompt or messages")
+    if not 1 <= request.top_k <= settings.logits_max_top_k:
+        raise HTTPException(status_code=400,
+                            detail=f"top_k must be between 1 and {settings.logits_max_top_k}")
+    base_url = upstream_url(request.model)
+    try:
+        text = request.prompt
+        if text is None:
+            resp = await http_client.post(f"{base_url}/apply-template", json={
+                "messages": [m.model_dump(exclude_none=True) for m in request.messages]})
+            resp.raise_for_status()
+            text = resp.json()["prompt"]
+        # Generating a single token yields the distribution it was drawn from;
+        # llama.cpp reports it before the sampler chain, so sampling settings don't apply
+        resp = await http_client.post(f"{base_url}/completion", json={
+            "prompt": text, "n_predict": 1, "n_probs": request.top_k, "cache_prompt": True})
+        resp.raise_for_status()
+        data = resp.json()
+    except httpx.RequestError as e:
+        raise HTTPException(status_code=502, detail=f"Upstream request error: {str(e)}")
+    except httpx.HTTPStatusError as e:
+        unavailable = upstream_unavailable_error(e.response.status_code, e.response.text)
+        if unavailable:
+            raise unavailable
+        raise HTTPException(status_code=500, detail=f"Upstream server error: {str(e)}")
+    return {
+        "object": "list",
+        "model": request.model,
+        "data": next_token_candidates(data)[:request.top_k],
+        "usage": {"prompt_tokens": data.get("tokens_evaluated")},
+    }
+
+
+@app.post("/v1/tokenize")
+async def tokenize_text(request: TokenizeRequest):
+    if (request.content is None) == (request.messages is None):
+        raise HTTPException(status_code=400, detail="Provide exactly one of content or messages")
+    base_url = upstream_url(request.model)
+    try:
+        text = request.content
+        if text is None:
+            # Counts exactly what a chat request's prompt_tokens would be
+            text = await apply_chat_template(base_url, [m.model_dump(exclude_none=True)
+                                                        for m in request.messages])
+        resp = await http_client.post(f"{base_url}/tokenize",
+                                      json={"content": text, "with_pieces": request.with_pieces})
+        resp.raise_for_status()
+        tokens = resp.json().get("tokens", [])
+    except httpx.RequestError as e:
+        raise HTTPException(status_code=502, detail=f"Upstream request error: {str(e)}")
+    except httpx.HTTPStatusError as e:
+        raise HTTPException(status_code=500, detail=f"Upstream server error: {str(e)}")
+    data = {"model": request.model, "count": len(tokens)}
+    if request.with_pieces:
+        # llama-server reports pieces as {"id", "piece"} objects
+        data["tokens"] = [token["id"] for token in tokens]
+        data["pieces"] = [token["piece"] for token in tokens]
+    else:
+        data["tokens"] = tokens
+    return data
+
+
+@app.post("/v1/detokenize")
+async def detokenize_tokens(request: DetokenizeRequest):
+    base_url = upstream_url(request.model)
+    try:
+        resp = await http_client.post(f"{base_url}/detokenize", json={"tokens": request.tokens})
+        resp.raise_for_status()
+    except httpx.RequestError as e:
+        raise HTTPException(status_code=502, detail=f"Upstream request error: {str(e)}")
+    except httpx.HTTPStatusError as e:
+        raise HTTPException(status_code=500, detail=f"Upstream server error: {str(e)}")
+    return {"model": request.model, "content": resp.json().get("content", "")}
+
+
+def anthropic_text(content: Union[str, List[dict], None]) -> str:
+    """Flatten Anthropic content blocks; only text blocks are supported."""
+    if content is None or isinstance(content, str):
+        return content or ""
+    parts = []
+    for block in content:
+        if block.get("type") != "text":
+            raise HTTPException(status_code=400,
+                                detail=f"Unsupported content block type: {block.get('type')}")
+        parts.append(block.get("text", ""))
+    return "\n".join(parts)
+
+
+ANTHROPIC_STOP_REASONS = {"stop": "end_turn", "length": "max_tokens", "tool_calls": "tool_use",
+                          "content_filter": "refusal"}
+
+
+@app.post("/v1/messages")
+async def anthropic_messages(request: AnthropicRequest, response: Response, prompt: Optional[str] = None):
+    if request.stream:
+        raise HTTPException(status_code=400, detail="Streaming is not supported on /v1/messages")
+    messages = [Message(role=m.role, content=anthropic_text(m.content)) for m in request.messages]
+    if request.system:
+        messages.insert(0, Message(role="system", content=anthropic_text(request.system)))
+    chat_request = ChatRequest(model=request.model, messages=messages, max_tokens=request.max_tokens)
+    if request.temperature is not None:
+        chat_request.temperature = request.temperature
+
+    data = await run_chat(chat_request, response, prompt=prompt)
 
-    return StreamingResponse(response_generator(), media_type="application/json")
+    choice = (data.get("choices") or [{}])[0]
+    usage = data.get("usage") or {}
+    return {
+        "id": "msg_" + str(data.get("id", "")).split("-", 1)[-1],
+        "type": "message",
+        "role": "assistant",
+        "model": request.model,
+        "content": [{"type": "text", "text": (choice.get("message") or {}).get("content") or ""}],
+        "stop_reason": ANTHROPIC_STOP_REASONS.get(choice.get("finish_reason"), "end_turn"),
+        "stop_sequence": None,
+        "usage": {
+            "input_tokens": usage.get("prompt_tokens", 0),
+            "output_tokens": usage.get("completion_tokens", 0),
+        },
+    }
 
 if __name__ == "__main__":
     import uvicorn
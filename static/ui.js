@@ -0,0 +1,123 @@
+"use strict";
+
+const log = document.getElementById("log");
+const form = document.getElementById("form");
+const input = document.getElementById("input");
+const sendButton = document.getElementById("send");
+const history = [];
+
+function addMessage(role, text) {
+  const el = document.createElement("div");
+  el.className = "msg " + role;
+  el.textContent = text;
+  log.appendChild(el);
+  log.scrollTop = log.scrollHeight;
+  return el;
+}
+
+async function loadPrompts() {
+  try {
+    const resp = await fetch("/prompts");
+    const data = await resp.json();
+    const select = document.getElementById("prompt");
+    for (const name of data.prompts) {
+      const option = document.createElement("option");
+      option.value = option.textContent = name;
+      select.appendChild(option);
+    }
+  } catch (err) {
+    addMessage("error", "Could not load prompts: " + err);
+  }
+}
+
+function requestUrl(path) {
+  const prompt = document.getElementById("prompt").value;
+  return prompt ? path + "?prompt=" + encodeURIComponent(prompt) : path;
+}
+
+async function readStream(resp, el) {
+  const reader = resp.body.getReader();
+  const decoder = new TextDecoder();
+  let buffer = "";
+  let text = "";
+  for (;;) {
+    const { done, value } = await reader.read();
+    if (done) break;
+    buffer += decoder.decode(value, { stream: true });
+    const lines = buffer.split("\n");
+    buffer = lines.pop();
+    for (const line of lines) {
+      if (!line.startsWith("data:")) continue;
+      const data = line.slice(5).trim();
+      if (data === "[DONE]") continue;
+      const chunk = JSON.parse(data);
+      for (const choice of chunk.choices || []) {
+        text += (choice.delta && choice.delta.content) || "";
+      }
+      el.textContent = text;
+      log.scrollTop = log.scrollHeight;
+    }
+  }
+  return text;
+}
+
+async function send(content) {
+  history.push({ role: "user", content });
+  addMessage("user", content);
+  const stream = document.getElementById("stream").checked;
+  const body = JSON.stringify({
+    model: document.getElementById("model").value,
+    messages: history,
+    max_tokens: 512,
+  });
+  const path = stream ? "/v1/chat/completions/stream" : "/v1/chat/completions";
+  const el = addMessage("assistant", "…");
+  const resp = await fetch(requestUrl(path), {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body,
+  });
+  if (!resp.ok) {
+    const err = await resp.json().catch(() => ({}));
+    el.className = "msg error";
+    el.textContent = "Error " + resp.status + ": " + JSON.stringify(err.detail || err);
+    history.pop();
+    return;
+  }
+  let text;
+  if (stream) {
+    text = await readStream(resp, el);
+  } else {
+    const data = await resp.json();
+    text = data.choices[0].message.content || "";
+    el.textContent = text;
+  }
+  history.push({ role: "assistant", content: text });
+}
+
+form.addEventListener("submit", async (event) => {
+  event.preventDefault();
+  const content = input.value.trim();
+  if (!content) return;
+  input.value = "";
+  sendButton.disabled = true;
+  try {
+    await send(content);
+  } catch (err) {
+    addMessage("error", String(err));
+  } finally {
+    sendButton.disabled = false;
+    input.focus();
+  }
+});
+
+input.addEventListener("keydown", (event) => {
+  if (event.key === "Enter" && event.ctrlKey) form.requestSubmit();
+});
+
+document.getElementById("clear").addEventListener("click", () => {
+  history.length = 0;
+  log.replaceChildren();
+});
+
+loadPrompts();
@@ -0,0 +1,79 @@
+"""Shared fixtures: the API runs in-process with MOCK_MODEL standing in for llama-server."""
+import json
+import os
+import sys
+
+# MOCK_MODEL is read once when server.py is imported, so it must be set first
+os.environ["MOCK_MODEL"] = "true"
+# Likewise CORS is configured at import time
+os.environ["CORS_ALLOW_ORIGINS"] = "https://app.example.com"
+sys.path.insert(0, os.path.dirname(os.path.dirname(os.path.abspath(__file__))))
+
+import pytest  # noqa: E402
+from fastapi.testclient import TestClient  # noqa: E402
+
+import server  # noqa: E402
+
+
+@pytest.fixture(autouse=True)
+def fresh_state(monkeypatch):
+    """Undo what one test could leak into the next through module-level state."""
+    monkeypatch.setattr(server, "memory_pressure_until", 0.0)
+    monkeypatch.setattr(server.mock_upstream, "last_prompt", [])
+    monkeypatch.setattr(server, "conversations", server.OrderedDict())
+    monkeypatch.setattr(server.upstream_health, "last_status", None, raising=False)
+    yield
+
+
+@pytest.fixture
+def client():
+    # Not used as a context manager: startup hooks install signal handlers, which only
+    # work on the main thread
+    return TestClient(server.app)
+
+
+@pytest.fixture
+def configure(monkeypatch):
+    """Override settings for one test, e.g. configure(max_output_bytes=16)."""
+    def apply(**values):
+        for name, value in values.items():
+            assert hasattr(server.settings, name), name
+            monkeypatch.setattr(server.settings, name, value)
+    return apply
+
+
+@pytest.fixture
+def sent(monkeypatch):
+    """Payloads that non-streamed chat requests hand to llama-server, in order."""
+    payloads = []
+    real = server.upstream_completion
+
+    async def spy(base_url, payload):
+        payloads.append(payload)
+        return await real(base_url, payload)
+
+    monkeypatch.setattr(server, "upstream_completion", spy)
+    return payloads
+
+
+def user(content: str) -> list:
+    return [{"role": "user", "content": content}]
+
+
+def chat(client, content: str, **fields):
+    return client.post("/v1/chat/completions", json={"model": "default", "messages": user(content), **fields})
+
+
+def stream_events(response) -> list:
+    """The decoded `data:` payloads of an SSE response, "[DONE]" included."""
+    events = []
+    for line in response.iter_lines():
+        if line.startswith("data:"):
+            data = line[len("data:"):].strip()
+            events.append(data if data == "[DONE]" else json.loads(data))
+    return events
+
+
+def streamed_content(events: list) -> str:
+    return "".join((choice.get("delta") or {}).get("content") or ""
+                   for event in events if event != "[DONE]" for choice in event.get("choices", []))
@@ -0,0 +1,40 @@
+import pytest
+
+import server
+
+
+@pytest.fixture
+def admin(configure, monkeypatch, tmp_path):
+    configure(admin_api_key="secret")
+    monkeypatch.setattr(server, "NOT_READY_FILE", str(tmp_path / "not-ready"))
+    return {"X-Admin-Key": "secret"}
+
+
+def test_disabled_without_an_admin_key(client):
+    assert client.post("/admin/abort-all").status_code == 403
+
+
+def test_wrong_key_is_refused(client, admin):
+    assert client.post("/admin/abort-all", headers={"X-Admin-Key": "wrong"}).status_code == 401
+
+
+def test_aborts_registered_inferences(client, admin):
+    inference = server.ActiveInference()
+    try:
+        response = client.post("/admin/abort-all", headers=admin)
+    finally:
+        inference.done()
+    assert response.status_code == 200
+    assert response.json() == {"aborted_here": 1, "other_workers_signalled": 0, "not_ready": False}
+    assert inference.aborted
+
+
+def test_not_ready_until_restored(client, admin):
+    assert client.post("/admin/abort-all", params={"not_ready": "true"}, headers=admin).status_code == 200
+    response = client.get("/healthz")
+    assert response.status_code == 503
+    assert response.json()["status"] == "not_ready"
+    assert client.get("/admin/model-status", headers=admin).status_code == 200
+
+    assert client.post("/admin/ready", headers=admin).status_code == 200
+    assert client.get("/healthz").status_code == 200
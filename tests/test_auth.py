@@ -0,0 +1,50 @@
+import pytest
+
+ORIGIN = "https://app.example.com"
+CHAT = {"model": "default", "messages": [{"role": "user", "content": "ping"}]}
+
+
+@pytest.fixture
+def keys(configure):
+    configure(api_keys=["sk-test"], auth_exempt_paths=["/", "/healthz", "/metrics"])
+
+
+def test_requests_without_a_key_are_rejected(client, keys):
+    response = client.post("/v1/chat/completions", json=CHAT)
+    assert response.status_code == 401
+    assert response.headers["WWW-Authenticate"] == "Bearer"
+
+
+@pytest.mark.parametrize("headers", [{"Authorization": "Bearer sk-test"}, {"X-API-Key": "sk-test"}])
+def test_either_header_carries_the_key(client, keys, headers):
+    assert client.post("/v1/chat/completions", json=CHAT, headers=headers).status_code == 200
+
+
+def test_wrong_key_is_rejected(client, keys):
+    response = client.post("/v1/chat/completions", json=CHAT, headers={"Authorization": "Bearer sk-other"})
+    assert response.status_code == 401
+
+
+def test_preflight_needs_no_key(client, keys):
+    response = client.options("/v1/chat/completions", headers={
+        "Origin": ORIGIN, "Access-Control-Request-Method": "POST",
+        "Access-Control-Request-Headers": "authorization,content-type"})
+    assert response.status_code == 200
+    assert response.headers["Access-Control-Allow-Origin"] == ORIGIN
+
+
+def test_rejections_carry_cors_headers(client, keys):
+    response = client.post("/v1/chat/completions", json=CHAT, headers={"Origin": ORIGIN})
+    assert response.status_code == 401
+    assert response.headers["Access-Control-Allow-Origin"] == ORIGIN
+
+
+def test_probes_are_exempt(client, keys):
+    assert client.get("/").status_code == 200
+    assert client.get("/healthz").status_code == 200
+
+
+def test_exempt_prefix(client, configure):
+    configure(api_keys=["sk-test"], auth_exempt_paths=["/v1/models*"])
+    assert client.get("/v1/models").status_code == 200
+    assert client.get("/healthz").status_code == 401
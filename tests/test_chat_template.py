@@ -0,0 +1,39 @@
+import logging
+
+import pytest
+
+import server
+from conftest import chat, user
+
+
+@pytest.fixture
+def untemplated(monkeypatch):
+    """A real (not mock) model whose GGUF carries no template, with no CHAT_TEMPLATE set."""
+    monkeypatch.setattr(server, "MOCK_MODEL", False)
+    monkeypatch.setattr(server, "default_template_warned", set())
+    monkeypatch.setattr(server, "gguf_has_chat_template", lambda path: False)
+    monkeypatch.delenv("CHAT_TEMPLATE", raising=False)
+    monkeypatch.delenv("CHAT_TEMPLATE_STRING", raising=False)
+
+
+def test_required_template_refuses_chats(client, configure, untemplated):
+    configure(require_chat_template=True)
+    response = chat(client, "ping")
+    assert response.status_code == 400
+    assert "CHAT_TEMPLATE" in response.json()["detail"]
+    stream = client.post("/v1/chat/completions/stream", json={"model": "default", "messages": user("ping")})
+    assert stream.status_code == 400
+
+
+def test_default_template_is_used_with_one_warning(client, configure, untemplated, caplog):
+    configure(require_chat_template=False)
+    with caplog.at_level(logging.WARNING):
+        assert chat(client, "ping").status_code == 200
+        assert chat(client, "ping").status_code == 200
+    assert sum("has no chat template" in record.getMessage() for record in caplog.records) == 1
+
+
+def test_chat_template_setting_satisfies_the_requirement(client, configure, untemplated, monkeypatch):
+    configure(require_chat_template=True)
+    monkeypatch.setenv("CHAT_TEMPLATE", "chatml")
+    assert chat(client, "ping").status_code == 200
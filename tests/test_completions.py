@@ -0,0 +1,90 @@
+import logging
+import re
+
+import server
+from conftest import stream_events
+
+
+def complete(client, prompt: str, **fields):
+    return client.post("/v1/completions", json={"model": "default", "prompt": prompt, **fields})
+
+
+def streamed(client, prompt: str, **fields) -> tuple:
+    """(text, finish_reason) of a streamed legacy completion."""
+    events = [e for e in stream_events(complete(client, prompt, stream=True, **fields)) if e != "[DONE]"]
+    text = "".join(choice.get("text") or "" for event in events for choice in event.get("choices", []))
+    finish = [choice["finish_reason"] for event in events for choice in event.get("choices", [])
+              if choice.get("finish_reason")]
+    return text, finish[-1]
+
+
+def test_raw_prompt_is_completed(client):
+    choice = complete(client, "def f():").json()["choices"][0]
+    assert choice["text"] == "Mock reply: def f():"
+    assert streamed(client, "def f():") == ("Mock reply: def f():", "stop")
+
+
+def test_max_prompt_tokens_counts_the_raw_prompt(client, configure):
+    configure(max_prompt_tokens=3)
+    response = complete(client, "one two three four")
+    assert response.status_code == 400
+    assert "maximum is 3" in response.json()["detail"]
+    assert complete(client, "one two", stream=True).status_code == 200
+
+
+def test_content_filter_applies(client, configure):
+    configure(output_filter_pattern=re.compile("secret"), output_filter_action="redact")
+    choice = complete(client, "the secret").json()["choices"][0]
+    assert choice["text"] == "Mock reply: the [REDACTED]"
+    assert choice["finish_reason"] == "content_filter"
+    # The chunk that completes the match is never sent
+    assert streamed(client, "the secret") == ("Mock reply: the ", "content_filter")
+
+
+def test_max_output_bytes_applies(client, configure):
+    configure(max_output_bytes=8)
+    choice = complete(client, "ping").json()["choices"][0]
+    assert choice["text"] == "Mock rep"
+    assert choice["finish_reason"] == "length"
+    assert streamed(client, "ping") == ("Mock rep", "length")
+
+
+def test_repetition_guard_applies(client, configure):
+    configure(ngram_repeat_limit=2, ngram_size=1)
+    choice = complete(client, "go go go go go").json()["choices"][0]
+    assert choice["text"] == "Mock reply: go go "
+    assert choice["finish_reason"] == "repetition"
+    assert streamed(client, "go go go go go") == ("Mock reply: go go ", "repetition")
+
+
+def test_adaptive_max_tokens_applies(client, configure, monkeypatch):
+    configure(adaptive_max_tokens=True, adaptive_max_tokens_threshold=1, adaptive_max_tokens_floor=2)
+    monkeypatch.setattr(server, "in_flight_requests", 3)
+    response = complete(client, "one two three four five", max_tokens=12)
+    # Four requests in flight against a threshold of one
+    assert response.headers["X-Max-Tokens-Cap"] == "3"
+    assert response.json()["choices"][0]["finish_reason"] == "length"
+
+
+def test_upstream_errors_report_partial_usage(client, configure):
+    configure(upstream_retries=0)
+    response = complete(client, "mock:error:500")
+    assert response.status_code == 500
+    assert response.json()["usage"]["prompt_tokens"] == 1
+
+
+def test_prompt_and_completion_are_logged(client, configure, caplog):
+    configure(log_prompts=True, log_sample_rate=1.0, log_redact_pattern=None)
+    with caplog.at_level(logging.DEBUG, logger="mindforge"):
+        complete(client, "ping")
+    messages = [record.getMessage() for record in caplog.records]
+    assert "prompt: ping" in messages
+    assert "completion: Mock reply: ping" in messages
+
+
+def test_failures_are_logged(client, configure, caplog):
+    configure(upstream_retries=0)
+    with caplog.at_level(logging.WARNING, logger="mindforge"):
+        complete(client, "mock:error:500")
+    assert any("completion failed" in record.getMessage() and "status=500" in record.getMessage()
+               for record in caplog.records)
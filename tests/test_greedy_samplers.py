@@ -0,0 +1,35 @@
+import server
+from conftest import chat
+
+
+def test_temperature_zero_drops_truncation_samplers(client, sent):
+    chat(client, "ping", temperature=0, top_p=0.1, top_k=5, typical_p=0.5, mirostat=2)
+    assert sent[0]["temperature"] == 0
+    for field in ("top_p", "top_k", "typical_p", "mirostat"):
+        assert field not in sent[0]
+
+
+def test_greedy_requests_reach_the_model_identically(client, sent):
+    first = chat(client, "ping", temperature=0, top_p=0.1).json()
+    second = chat(client, "ping", temperature=0, top_p=0.9, top_k=1).json()
+    assert sent[0] == sent[1]
+    assert first["choices"] == second["choices"]
+
+
+def test_samplers_are_kept_when_sampling(client, sent):
+    chat(client, "ping", temperature=0.7, top_p=0.1, top_k=5)
+    assert sent[0]["top_p"] == 0.1
+    assert sent[0]["top_k"] == 5
+
+
+def test_ignored_samplers_are_reported(client):
+    response = client.post("/v1/chat/completions?warnings=true",
+                           json={"model": "default", "messages": [{"role": "user", "content": "ping"}],
+                                 "temperature": 0, "top_p": 0.1})
+    assert "top_p ignored: temperature 0 decodes greedily" in response.json()["warnings"]
+
+
+def test_legacy_completions_drop_them_too():
+    payload = server.completion_payload(
+        server.CompletionRequest(model="default", prompt="ping", temperature=0, top_p=0.1, top_k=5))
+    assert "top_p" not in payload and "top_k" not in payload
@@ -0,0 +1,30 @@
+import server
+
+
+def test_ready_when_upstream_answers(client):
+    response = client.get("/healthz")
+    assert response.status_code == 200
+    assert response.json()["status"] == "ok"
+
+
+def test_draining_fails_readiness_but_not_liveness(client, monkeypatch):
+    monkeypatch.setattr(server, "draining", True)
+    response = client.get("/healthz")
+    assert response.status_code == 503
+    assert response.json() == {"status": "draining", "reason": "draining"}
+    assert client.get("/").status_code == 200
+
+
+def test_model_status_needs_the_admin_key(client, configure):
+    assert client.get("/admin/model-status").status_code == 403
+    configure(admin_api_key="secret")
+    assert client.get("/admin/model-status").status_code == 401
+    assert client.get("/admin/model-status", headers={"X-Admin-Key": "wrong"}).status_code == 401
+
+
+def test_model_status_still_answers_while_draining(client, configure, monkeypatch):
+    configure(admin_api_key="secret")
+    monkeypatch.setattr(server, "draining", True)
+    response = client.get("/admin/model-status", headers={"X-Admin-Key": "secret"})
+    assert response.status_code == 200
+    assert response.json()["models"] == {"default": "ready"}
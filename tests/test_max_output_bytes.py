@@ -0,0 +1,23 @@
+from conftest import chat
+
+WEATHER_TOOL = {"type": "function", "function": {
+    "name": "get_weather", "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}}}
+
+
+def test_output_is_cut_at_the_byte_cap(client, configure):
+    configure(max_output_bytes=8)
+    choice = chat(client, "ping").json()["choices"][0]
+    assert choice["message"]["content"] == "Mock rep"
+    assert choice["finish_reason"] == "length"
+
+
+def test_tool_calls_survive_the_internal_stream(client, configure):
+    configure(max_output_bytes=1000)
+    response = chat(client, 'mock:tool:get_weather {"city": "Paris"}', tools=[WEATHER_TOOL])
+    assert response.status_code == 200
+    choice = response.json()["choices"][0]
+    assert choice["finish_reason"] == "tool_calls"
+    assert choice["message"]["content"] is None
+    assert choice["message"]["tool_calls"] == [{
+        "id": "call_mock", "type": "function",
+        "function": {"name": "get_weather", "arguments": '{"city": "Paris"}'}}]
@@ -0,0 +1,48 @@
+import server
+
+
+def debug_prompt(client, messages, **fields):
+    response = client.post("/v1/chat/completions?debug_prompt=true",
+                           json={"model": "default", "messages": messages, **fields})
+    assert response.status_code == 200
+    return response.json()["debug"]["prompt"]
+
+
+TWO_USER_TURNS = [{"role": "user", "content": "first"}, {"role": "user", "content": "second"}]
+
+
+def test_adjacent_same_role_messages_are_joined():
+    merged = server.merge_consecutive_roles(
+        [{"role": "system", "content": "a"}, {"role": "system", "content": "b"},
+         {"role": "user", "content": "c"}, {"role": "assistant", "content": "d"},
+         {"role": "assistant", "content": "e"}], " | ")
+    assert merged == [{"role": "system", "content": "a | b"}, {"role": "user", "content": "c"},
+                      {"role": "assistant", "content": "d | e"}]
+
+
+def test_tool_calls_and_results_are_left_alone():
+    tool_call = {"id": "call_1", "type": "function", "function": {"name": "f", "arguments": "{}"}}
+    messages = [{"role": "assistant", "content": None, "tool_calls": [tool_call]},
+                {"role": "assistant", "content": "done"},
+                {"role": "tool", "tool_call_id": "call_1", "content": "1"},
+                {"role": "tool", "tool_call_id": "call_2", "content": "2"}]
+    assert server.merge_consecutive_roles(messages, "\n\n") == messages
+
+
+def test_input_messages_are_not_modified():
+    messages = [dict(m) for m in TWO_USER_TURNS]
+    server.merge_consecutive_roles(messages, "\n\n")
+    assert messages == TWO_USER_TURNS
+
+
+def test_setting_merges_with_the_configured_separator(client, configure):
+    configure(merge_consecutive_roles=True, merge_roles_separator="\n---\n")
+    assert debug_prompt(client, TWO_USER_TURNS) == "<|user|>\nfirst\n---\nsecond\n<|assistant|>\n"
+
+
+def test_request_field_overrides_the_setting(client, configure):
+    configure(merge_consecutive_roles=False)
+    assert "<|user|>\nfirst\n\nsecond\n" in debug_prompt(client, TWO_USER_TURNS, merge_consecutive_roles=True)
+    configure(merge_consecutive_roles=True)
+    prompt = debug_prompt(client, TWO_USER_TURNS, merge_consecutive_roles=False)
+    assert prompt.count("<|user|>") == 2
@@ -0,0 +1,47 @@
+from conftest import chat, stream_events, streamed_content, user
+
+
+def test_chat_reply_echoes_prompt_tail(client):
+    response = chat(client, "ping")
+    assert response.status_code == 200
+    body = response.json()
+    assert body["choices"][0]["message"]["content"] == "Mock reply: ping"
+    assert body["choices"][0]["finish_reason"] == "stop"
+    assert body["usage"]["completion_tokens"] == 3
+
+
+def test_responses_are_marked_as_mock(client):
+    assert chat(client, "ping").headers["X-Mock-Model"] == "true"
+    assert client.get("/").headers["X-Mock-Model"] == "true"
+
+
+def test_max_tokens_cuts_the_reply(client):
+    choice = chat(client, "one two three", max_tokens=2).json()["choices"][0]
+    assert choice["message"]["content"] == "Mock reply: "
+    assert choice["finish_reason"] == "length"
+
+
+def test_stream_delivers_the_same_reply(client):
+    response = client.post("/v1/chat/completions/stream", json={"model": "default", "messages": user("ping")})
+    assert response.status_code == 200
+    assert response.headers["X-Mock-Model"] == "true"
+    events = stream_events(response)
+    assert events[-1] == "[DONE]"
+    assert streamed_content(events) == "Mock reply: ping"
+    assert events[-2]["choices"][0]["finish_reason"] == "stop"
+
+
+def test_upstream_error_is_reported(client, configure):
+    configure(upstream_retries=0)
+    response = chat(client, "mock:error:500")
+    assert response.status_code == 500
+    assert "Upstream server error" in response.json()["detail"]
+
+
+def test_plain_upstream_503_passes_through_without_lockout(client, configure):
+    configure(upstream_retries=0)
+    response = chat(client, "mock:error:503")
+    assert response.status_code == 503
+    assert response.headers["Retry-After"] == "1"
+    # Only allocation failures refuse the requests that follow
+    assert chat(client, "ping").status_code == 200
@@ -0,0 +1,35 @@
+import pytest
+
+import server
+from conftest import chat
+
+
+def test_models_get_positional_ports():
+    models = server.parse_models("small=a.gguf, large=/abs/b.gguf:chatml")
+    assert models["small"] == {"path": "a.gguf", "template": None,
+                               "url": f"http://localhost:{server.UPSTREAM_BASE_PORT}"}
+    assert models["large"]["template"] == "chatml"
+    assert models["large"]["url"] == f"http://localhost:{server.UPSTREAM_BASE_PORT + 1}"
+
+
+def test_duplicate_model_names_are_rejected():
+    with pytest.raises(ValueError, match="Duplicate model name 'small'"):
+        server.parse_models("small=a.gguf,large=b.gguf, small =c.gguf")
+
+
+def test_reload_with_duplicate_models_keeps_the_previous_settings(client, monkeypatch):
+    monkeypatch.setattr(server, "settings", server.settings)
+    before = server.settings
+    monkeypatch.setenv("MODELS", "small=a.gguf,small=b.gguf")
+    server.reload_config()
+    assert server.settings is before
+    assert chat(client, "ping").status_code == 200
+
+
+def test_reload_keeps_the_startup_model_map(client, monkeypatch):
+    monkeypatch.setattr(server, "settings", server.settings)
+    models = server.settings.models
+    monkeypatch.setenv("MODELS", "other=c.gguf")
+    server.reload_config()
+    assert server.settings.models is models
+    assert chat(client, "ping").status_code == 200
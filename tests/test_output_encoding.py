@@ -0,0 +1,76 @@
+import asyncio
+import logging
+
+import httpx
+import pytest
+
+import server
+from conftest import chat, stream_events, streamed_content, user
+
+# A U+FFFD the model wrote, encoded as the valid UTF-8 it is
+REAL_FFFD = "�".encode()
+
+
+def test_undecodable_bytes_are_told_apart_from_a_real_replacement_character():
+    response = httpx.Response(200, content=b'{"choices": [{"text": "ok \xff\xfe done ' + REAL_FFFD + b'"}]}')
+    assert server.upstream_json(response)["choices"][0]["text"] == "ok \udcff\udcfe done �"
+
+
+def test_streamed_lines_are_decoded_the_same_way():
+    # The real U+FFFD is split across two chunks of the body
+    body = b'data: {"text": "a\xff"}\r\ndata: {"text": "' + REAL_FFFD + b'"}\n\ndata: [DONE]'
+    split = body.index(REAL_FFFD) + 1
+
+    async def chunks():
+        yield body[:split]
+        yield body[split:]
+
+    response = httpx.Response(200, content=chunks())
+
+    async def lines():
+        return [line async for line in server.upstream_lines(response)]
+
+    assert asyncio.run(lines()) == ['data: {"text": "a\udcff"}', 'data: {"text": "�"}', "", "data: [DONE]"]
+
+
+@pytest.mark.parametrize("policy, expected", [("replace", "ok � done �, real �"),
+                                              ("strip", "ok  done , real �")])
+def test_policy_applies_to_every_text_field(configure, policy, expected):
+    configure(output_encoding_policy=policy)
+    # A decoded bad byte, a lone surrogate escape, and a U+FFFD the model meant
+    text = "ok \udcff done \ud83d, real �"
+    data = {"choices": [{"message": {"content": text}}, {"delta": {"content": text}}, {"text": text}]}
+    server.sanitize_output_encoding(data)
+    assert data["choices"][0]["message"]["content"] == expected
+    assert data["choices"][1]["delta"]["content"] == expected
+    assert data["choices"][2]["text"] == expected
+
+
+def test_real_replacement_characters_are_not_reported(configure, caplog):
+    configure(output_encoding_policy="strip")
+    data = {"choices": [{"message": {"content": "héllo ✓ �"}}]}
+    with caplog.at_level(logging.WARNING):
+        server.sanitize_output_encoding(data)
+    assert data["choices"][0]["message"]["content"] == "héllo ✓ �"
+    assert not caplog.records
+
+
+def test_chat_reply_is_stripped(client, configure, caplog):
+    # The mock sends the surrogate as a JSON escape, as llama.cpp can
+    configure(output_encoding_policy="strip", mock_response="bad \udcff byte �")
+    with caplog.at_level(logging.WARNING):
+        content = chat(client, "ping").json()["choices"][0]["message"]["content"]
+    assert content == "bad  byte �"
+    assert any("invalid UTF-8" in record.getMessage() for record in caplog.records)
+
+
+def test_stream_is_stripped(client, configure):
+    configure(output_encoding_policy="strip", mock_response="bad \udcff byte �")
+    response = client.post("/v1/chat/completions/stream", json={"model": "default", "messages": user("ping")})
+    assert streamed_content(stream_events(response)) == "bad  byte �"
+
+
+def test_unknown_policy_is_rejected(monkeypatch):
+    monkeypatch.setenv("OUTPUT_ENCODING_POLICY", "ignore")
+    with pytest.raises(ValueError, match="OUTPUT_ENCODING_POLICY"):
+        server.Settings()
@@ -0,0 +1,31 @@
+FEW_SHOT = [{"role": "system", "content": "Translate to French."},
+            {"role": "user", "content": "cat"},
+            {"role": "assistant", "content": "chat"}]
+
+
+def debug(client, messages):
+    response = client.post("/v1/chat/completions?debug_prompt=true",
+                           json={"model": "default", "messages": messages})
+    assert response.status_code == 200
+    return response.json()
+
+
+def test_history_ending_with_user_opens_a_new_reply(client):
+    prompt = debug(client, FEW_SHOT + [{"role": "user", "content": "dog"}])["debug"]["prompt"]
+    # The few-shot turns stay in order and the primer follows the question
+    assert prompt.index("<|user|>\ncat") < prompt.index("<|assistant|>\nchat") < prompt.index("<|user|>\ndog")
+    assert prompt.endswith("<|user|>\ndog\n<|assistant|>\n")
+
+
+def test_history_ending_with_assistant_is_continued(client):
+    messages = FEW_SHOT + [{"role": "user", "content": "dog"}, {"role": "assistant", "content": "ch"}]
+    prompt = debug(client, messages)["debug"]["prompt"]
+    # No empty assistant turn after the prefill
+    assert prompt.endswith("<|assistant|>\nch")
+    assert prompt.count("<|assistant|>") == 2
+
+
+def test_reply_answers_the_last_user_turn(client):
+    messages = FEW_SHOT + [{"role": "user", "content": "dog"}]
+    body = debug(client, messages)
+    assert body["choices"][0]["message"]["content"] == "Mock reply: dog"
@@ -0,0 +1,44 @@
+import pytest
+
+import server
+from conftest import user
+
+
+@pytest.fixture
+def prompts_dir(tmp_path, monkeypatch):
+    monkeypatch.setattr(server, "PROMPTS_DIR", str(tmp_path))
+    server.read_prompt_file.cache_clear()
+    server.prompt_metadata.cache_clear()
+    yield tmp_path
+    server.read_prompt_file.cache_clear()
+    server.prompt_metadata.cache_clear()
+
+
+def chat_with_prompt(client, prompt: str):
+    return client.post(f"/v1/chat/completions?prompt={prompt}",
+                       json={"model": "default", "messages": user("ping")})
+
+
+def test_template_is_used_as_system_prompt(client, prompts_dir):
+    (prompts_dir / "helper.txt").write_text("Be brief.", encoding="utf-8")
+    response = client.post("/v1/chat/completions?prompt=helper&debug_prompt=true",
+                           json={"model": "default", "messages": user("ping")})
+    assert response.status_code == 200
+    assert "<|system|>\nBe brief." in response.json()["debug"]["prompt"]
+
+
+def test_invalid_utf8_names_the_template_and_offset(client, prompts_dir):
+    (prompts_dir / "latin1.txt").write_bytes("Répondez".encode("latin-1"))
+    response = chat_with_prompt(client, "latin1")
+    assert response.status_code == 500
+    detail = response.json()["detail"]
+    assert "latin1" in detail
+    assert "invalid byte 0xe9 at offset 1" in detail
+
+
+def test_invalid_utf8_in_an_included_template_is_reported(client, prompts_dir):
+    (prompts_dir / "outer.txt").write_text("Intro\n{{include:inner}}", encoding="utf-8")
+    (prompts_dir / "inner.txt").write_bytes(b"ok \xff")
+    detail = chat_with_prompt(client, "outer").json()["detail"]
+    assert "inner is not valid UTF-8" in detail
+    assert "0xff at offset 3" in detail
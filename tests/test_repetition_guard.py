@@ -0,0 +1,27 @@
+from conftest import chat
+from test_max_output_bytes import WEATHER_TOOL
+
+
+def test_looping_output_ends_with_repetition(client, configure):
+    configure(mock_response="la la la la la la", ngram_repeat_limit=2, ngram_size=1)
+    choice = chat(client, "sing").json()["choices"][0]
+    assert choice["message"]["content"] == "la la "
+    assert choice["finish_reason"] == "repetition"
+
+
+def test_request_can_turn_the_guard_off(client, configure):
+    configure(mock_response="la la la la la la", ngram_repeat_limit=2, ngram_size=1)
+    choice = chat(client, "sing", ngram_repeat_limit=0).json()["choices"][0]
+    assert choice["message"]["content"] == "la la la la la la"
+    assert choice["finish_reason"] == "stop"
+
+
+def test_tool_calls_survive_every_internal_stream_trigger(client, configure):
+    configure(ngram_repeat_limit=3)
+    for fields, params in (({}, {}), ({"stop": ["END"]}, {}), ({}, {"profile": "true"})):
+        response = client.post("/v1/chat/completions", params=params, json={
+            "model": "default", "tools": [WEATHER_TOOL], **fields,
+            "messages": [{"role": "user", "content": "mock:tool:get_weather"}]})
+        assert response.status_code == 200, fields or params
+        message = response.json()["choices"][0]["message"]
+        assert message["tool_calls"][0]["function"] == {"name": "get_weather", "arguments": "{}"}
@@ -0,0 +1,40 @@
+from conftest import chat
+
+
+def reply(client, seed=None, temperature=0.8) -> dict:
+    fields = {"temperature": temperature} if seed is None else {"seed": seed, "temperature": temperature}
+    response = chat(client, "Name a colour", **fields)
+    assert response.status_code == 200
+    return response.json()
+
+
+def test_same_seed_gives_the_same_output(client):
+    first, second = reply(client, 42), reply(client, 42)
+    assert first["seed"] == second["seed"] == 42
+    assert first["choices"] == second["choices"]
+
+
+def test_different_seeds_give_different_output(client):
+    # The mock picks a seed-dependent word, so this fails if the seed never reaches the sampler
+    assert reply(client, 42)["choices"][0]["message"]["content"] == "Mock reply: Name a colour amber"
+    assert reply(client, 43)["choices"][0]["message"]["content"] == "Mock reply: Name a colour violet"
+
+
+def test_greedy_output_ignores_the_seed(client):
+    assert reply(client, 42, temperature=0)["choices"] == reply(client, 43, temperature=0)["choices"]
+
+
+def test_fingerprint_identifies_the_backend_not_the_request(client):
+    fingerprints = {reply(client, seed)["system_fingerprint"] for seed in (1, 2, None)}
+    assert len(fingerprints) == 1
+    assert fingerprints.pop().startswith("fp_")
+
+
+def test_seed_is_sent_to_the_sampler_without_prompt_caching(client, sent):
+    reply(client, 42)
+    assert sent[0]["seed"] == 42
+    assert sent[0]["cache_prompt"] is False
+
+
+def test_seed_out_of_range_is_rejected(client):
+    assert chat(client, "ping", seed=0xFFFFFFFF).status_code == 400
@@ -0,0 +1,22 @@
+from conftest import chat, user
+
+
+def test_raw_output_is_refused_while_llama_cpp_drops_control_tokens(client, configure):
+    configure(skip_special_tokens=True)
+    response = chat(client, "ping", skip_special_tokens=False)
+    assert response.status_code == 400
+    assert response.json()["details"][0]["field"] == "skip_special_tokens"
+    stream = client.post("/v1/chat/completions/stream",
+                         json={"model": "default", "messages": user("ping"), "skip_special_tokens": False})
+    assert stream.status_code == 400
+
+
+def test_clean_output_is_the_default(client, configure):
+    configure(skip_special_tokens=True)
+    assert chat(client, "ping", skip_special_tokens=True).status_code == 200
+
+
+def test_request_chooses_when_control_tokens_are_rendered(client, configure):
+    configure(skip_special_tokens=False, mock_response="Hi<|end|>")
+    assert chat(client, "ping", skip_special_tokens=False).json()["choices"][0]["message"]["content"] == "Hi<|end|>"
+    assert chat(client, "ping").json()["choices"][0]["message"]["content"] == "Hi"
@@ -0,0 +1,29 @@
+import time
+
+from conftest import chat
+
+
+def test_output_stops_at_the_first_match(client):
+    choice = chat(client, "ping", stop_regex=r"re\w+").json()["choices"][0]
+    assert choice["message"]["content"] == "Mock reply"
+    assert choice["finish_reason"] == "stop"
+
+
+def test_invalid_pattern_is_rejected(client):
+    response = chat(client, "ping", stop_regex="(")
+    assert response.status_code == 400
+    assert response.json()["details"][0]["field"] == "stop_regex"
+
+
+def test_pathological_patterns_are_rejected_before_any_search(client):
+    for pattern in (r"(a+)+b", r"(a*)*b", r"(a|aa)+b", r"(?:x(y+))*z"):
+        started = time.time()
+        response = chat(client, "a" * 64, stop_regex=pattern)
+        assert response.status_code == 400, pattern
+        assert "nested quantifiers" in response.json()["detail"]
+        assert time.time() - started < 1
+
+
+def test_ordinary_repetition_is_allowed(client):
+    for pattern in (r"\n```\n", r"(foo|bar)+", r"(ab)+c", r"[a-z]+\."):
+        assert chat(client, "ping", stop_regex=pattern).status_code == 200, pattern
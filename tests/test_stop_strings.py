@@ -0,0 +1,71 @@
+import pytest
+
+import server
+from conftest import stream_events, streamed_content, user
+
+# The mock replies "Mock reply: alpha beta gamma", one token per word:
+# "Mock ", "reply: ", "alpha ", "beta ", "gamma"
+PROMPT = "alpha beta gamma"
+
+
+def complete(client, stream: bool, **fields) -> tuple:
+    """(content, finish_reason) from the plain or streaming endpoint."""
+    body = {"model": "default", "messages": user(PROMPT), **fields}
+    if not stream:
+        choice = client.post("/v1/chat/completions", json=body).json()["choices"][0]
+        return choice["message"]["content"], choice["finish_reason"]
+    events = stream_events(client.post("/v1/chat/completions/stream", json=body))
+    finish = next(choice["finish_reason"] for event in reversed(events) if event != "[DONE]"
+                  for choice in event.get("choices", []) if choice.get("finish_reason"))
+    return streamed_content(events), finish
+
+
+@pytest.fixture(params=[False, True], ids=["json", "stream"])
+def stream(request):
+    return request.param
+
+
+def test_stop_split_across_chunks(client, stream):
+    assert complete(client, stream, stop="a be") == ("Mock reply: alph", "stop")
+
+
+def test_stop_at_chunk_offset_zero(client, stream):
+    assert complete(client, stream, stop="beta") == ("Mock reply: alpha ", "stop")
+
+
+def test_stop_at_the_very_start(client, stream):
+    assert complete(client, stream, stop="Mock") == ("", "stop")
+
+
+def test_case_insensitive_match(client, stream):
+    assert complete(client, stream, stop="BETA", stop_case_insensitive=True) == ("Mock reply: alpha ", "stop")
+
+
+def test_case_sensitive_by_default(client, stream):
+    assert complete(client, stream, stop="BETA") == ("Mock reply: alpha beta gamma", "stop")
+
+
+def test_whitespace_is_folded(client, stream):
+    assert complete(client, stream, stop="alpha\n\tbeta") == ("Mock reply: ", "stop")
+
+
+def test_earliest_of_several_stops_wins(client, stream):
+    assert complete(client, stream, stop=["gamma", "reply"]) == ("Mock ", "stop")
+
+
+def test_unmatched_prefix_is_released_at_the_end(client, stream):
+    # "gamma" could be the start of the stop until the stream ends
+    assert complete(client, stream, stop="gamma delta") == ("Mock reply: alpha beta gamma", "stop")
+
+
+def test_whitespace_only_text_is_held_back():
+    stops = [server.stop_segments("\nUser:")]
+    # A trailing whitespace token might start the stop, so it's held until decided
+    assert server.find_stop("Answer \n", stops, False) == (None, len("Answer"))
+    assert server.find_stop("Answer \nUser: hi", stops, False) == (len("Answer"), len("Answer"))
+
+
+def test_whitespace_only_stops_stay_literal():
+    stops = [server.stop_segments("\n\n")]
+    assert server.find_stop("one two", stops, False) == (None, len("one two"))
+    assert server.find_stop("one\n\ntwo", stops, False)[0] == 3
@@ -0,0 +1,35 @@
+from conftest import user
+
+SYSTEM = [{"role": "system", "content": "You are a terse assistant who answers in one line."}]
+
+
+def ask(client, **fields):
+    return client.post("/v1/chat/completions", json={"model": "default", "messages": SYSTEM + user("ping"), **fields})
+
+
+def test_repeated_system_prompt_hits_the_cache(client, configure):
+    configure(system_prompt_cache=True)
+    assert ask(client).headers["X-System-Prompt-Cache"] == "miss"
+    response = ask(client)
+    assert response.headers["X-System-Prompt-Cache"] == "hit"
+    assert response.json()["usage"]["prompt_tokens_details"]["cached_tokens"] > 0
+
+
+def test_seeded_requests_never_use_the_cache(client, configure):
+    configure(system_prompt_cache=True)
+    ask(client, seed=7)
+    response = ask(client, seed=7)
+    assert "X-System-Prompt-Cache" not in response.headers
+    assert response.json()["usage"]["prompt_tokens_details"]["cached_tokens"] == 0
+
+
+def test_partly_reused_system_prompt_is_a_miss(client, configure):
+    configure(system_prompt_cache=True)
+    ask(client)
+    # Only the last word differs, so llama.cpp reuses as many tokens as the bare
+    # system text has; the template's markup around it makes that a partial reuse
+    changed = [{"role": "system", "content": "You are a terse assistant who answers in one word."}]
+    response = client.post("/v1/chat/completions",
+                           json={"model": "default", "messages": changed + user("ping")})
+    assert response.headers["X-System-Prompt-Cache"] == "miss"
+    assert response.json()["usage"]["prompt_tokens_details"]["cached_tokens"] > 0
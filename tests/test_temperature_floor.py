@@ -0,0 +1,38 @@
+import pytest
+
+import server
+from conftest import chat
+
+
+@pytest.mark.parametrize("temperature", [0, 1e-9, 0.001, 0.0099])
+def test_temperatures_below_the_floor_decode_greedily(client, configure, sent, temperature):
+    configure(temperature_floor=0.01)
+    assert chat(client, "ping", temperature=temperature).status_code == 200
+    assert sent[0]["temperature"] == 0.0
+
+
+@pytest.mark.parametrize("temperature", [0.01, 0.5, 1.0])
+def test_temperatures_at_or_above_the_floor_are_kept(client, configure, sent, temperature):
+    configure(temperature_floor=0.01)
+    chat(client, "ping", temperature=temperature)
+    assert sent[0]["temperature"] == temperature
+
+
+def test_floor_of_zero_only_maps_zero(client, configure, sent):
+    configure(temperature_floor=0.0)
+    chat(client, "ping", temperature=1e-9)
+    assert sent[0]["temperature"] == 1e-9
+
+
+def test_floored_temperature_is_reported_as_a_warning(client, configure):
+    configure(temperature_floor=0.01)
+    response = client.post("/v1/chat/completions?warnings=true",
+                           json={"model": "default", "messages": [{"role": "user", "content": "ping"}],
+                                 "temperature": 0.001})
+    assert any("below TEMPERATURE_FLOOR" in warning for warning in response.json()["warnings"])
+
+
+def test_legacy_completions_apply_the_floor(configure):
+    configure(temperature_floor=0.01)
+    payload = server.completion_payload(server.CompletionRequest(model="default", prompt="ping", temperature=0.001))
+    assert payload["temperature"] == 0.0